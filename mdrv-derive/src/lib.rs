@@ -0,0 +1,100 @@
+//! Derive macros for [`Mdrv`] command sets.
+//!
+//! `#[derive(TxExt)]` and `#[derive(RxExt)]` are applied to the enum that embeds the basic
+//! `proxy_handle::Tx` or `proxy_handle::Rx` command in the variant marked with `#[mdrv(base)]`.
+//! They generate the `From` and `Into` conversions to the basic command and the marker trait impl.
+//!
+//! ```rust,ignore
+//! #[derive(Debug, TxExt)]
+//! pub enum Tx {
+//!     #[mdrv(base)]
+//!     Base(proxy_handle::Tx),
+//!     Cmd(String),
+//! }
+//! ```
+//!
+//! [`Mdrv`]: https://github.com/binp-automation/mdrv
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate quote;
+extern crate syn;
+
+use proc_macro::{TokenStream};
+use proc_macro2::{TokenStream as TokenStream2};
+use quote::{quote};
+use syn::{parse_macro_input, DeriveInput, Data, Fields, Ident, Error};
+
+
+#[proc_macro_derive(TxExt, attributes(mdrv))]
+pub fn derive_tx_ext(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, "Tx", "TxExt").unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+#[proc_macro_derive(RxExt, attributes(mdrv))]
+pub fn derive_rx_ext(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, "Rx", "RxExt").unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+/// Finds the single-field variant marked with `#[mdrv(base)]`.
+fn base_variant(input: &DeriveInput) -> Result<&Ident, Error> {
+    let data = match input.data {
+        Data::Enum(ref data) => data,
+        _ => return Err(Error::new_spanned(&input.ident, "command set should be an enum")),
+    };
+    let mut base = None;
+    for variant in data.variants.iter() {
+        let mut marked = false;
+        for attr in variant.attrs.iter().filter(|a| a.path().is_ident("mdrv")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("base") {
+                    marked = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown mdrv attribute"))
+                }
+            })?;
+        }
+        if !marked {
+            continue;
+        }
+        if base.is_some() {
+            return Err(Error::new_spanned(variant, "only one variant could be marked with #[mdrv(base)]"));
+        }
+        match variant.fields {
+            Fields::Unnamed(ref fields) if fields.unnamed.len() == 1 => (),
+            _ => return Err(Error::new_spanned(variant, "base variant should have exactly one unnamed field")),
+        }
+        base = Some(&variant.ident);
+    }
+    base.ok_or_else(|| Error::new_spanned(&input.ident, "no variant is marked with #[mdrv(base)]"))
+}
+
+fn expand(input: &DeriveInput, base: &str, ext: &str) -> Result<TokenStream2, Error> {
+    let variant = base_variant(input)?;
+    let name = &input.ident;
+    let base = Ident::new(base, name.span());
+    let ext = Ident::new(ext, name.span());
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::std::convert::From<::mdrv::proxy_handle::#base> for #name #ty_generics #where_clause {
+            fn from(other: ::mdrv::proxy_handle::#base) -> Self {
+                #name::#variant(other)
+            }
+        }
+
+        #[allow(clippy::from_over_into)]
+        impl #impl_generics ::std::convert::Into<::std::result::Result<::mdrv::proxy_handle::#base, Self>> for #name #ty_generics #where_clause {
+            fn into(self) -> ::std::result::Result<::mdrv::proxy_handle::#base, Self> {
+                match self {
+                    #name::#variant(base) => ::std::result::Result::Ok(base),
+                    other => ::std::result::Result::Err(other),
+                }
+            }
+        }
+
+        impl #impl_generics ::mdrv::proxy_handle::#ext for #name #ty_generics #where_clause {}
+    })
+}
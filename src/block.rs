@@ -0,0 +1,282 @@
+//! IEEE 488.2 arbitrary block data codec.
+//!
+//! Responses starting with `#` are decoded as binary blocks:
+//!
+//! + `#<n><len><data>` definite-length block where `<n>` is the number of digits in `<len>`,
+//! + `#0<data>\n` indefinite-length block terminated by the newline sent with END.
+//!
+//! Other responses, including the non-decimal numbers `#H`, `#Q` and `#B`, are decoded as newline-terminated lines.
+//! Over a byte stream the END message is not visible, so the first newline terminates the indefinite block.
+
+use std::io;
+
+use ::codec::{Codec};
+
+
+/// Decoded response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    /// Line without the terminator.
+    Line(Vec<u8>),
+    /// Payload of the binary block.
+    Block(Vec<u8>),
+}
+
+/// Codec of the responses containing lines and blocks.
+///
+/// Encoded messages are terminated with the newline if it is missing.
+pub struct BlockCodec {
+    /// Terminators following the definite-length block are skipped.
+    trailer: bool,
+    /// Number of bytes of the incomplete response already searched for the terminator.
+    scanned: usize,
+    max_len: usize,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl BlockCodec {
+    pub fn new() -> Self {
+        Self { trailer: false, scanned: 0, max_len: usize::MAX }
+    }
+
+    /// Sets the maximum declared length of the definite-length block, longer blocks are treated as invalid data.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Number of the skipped terminators at the beginning of the buffer.
+    fn skipped(&self, buf: &[u8]) -> usize {
+        if !self.trailer {
+            return 0;
+        }
+        buf.iter().take_while(|b| **b == b'\r' || **b == b'\n').count()
+    }
+
+    /// Position of the first newline in the buffer which is known to have none before `from`.
+    fn newline(buf: &[u8], from: usize) -> Option<usize> {
+        let from = from.min(buf.len());
+        buf[from..].iter().position(|b| *b == b'\n').map(|pos| from + pos)
+    }
+
+    fn line(buf: &[u8], from: usize) -> Option<(Item, usize)> {
+        Self::newline(buf, from).map(|pos| {
+            let line = buf[..pos].strip_suffix(b"\r").unwrap_or(&buf[..pos]);
+            (Item::Line(line.to_vec()), pos + 1)
+        })
+    }
+
+    fn block(&self, buf: &[u8], from: usize) -> io::Result<Option<(Item, usize)>> {
+        let digits = match buf.get(1) {
+            None => return Ok(None),
+            Some(b'0') => {
+                return Ok(Self::newline(buf, from.max(2)).map(|pos| {
+                    (Item::Block(buf[2..pos].to_vec()), pos + 1)
+                }));
+            },
+            Some(b @ b'1'..=b'9') => (b - b'0') as usize,
+            _ => return Err(invalid("bad block header")),
+        };
+        let mut len = 0usize;
+        for b in buf[2..].iter().take(digits) {
+            let digit = match *b {
+                b @ b'0'..=b'9' => (b - b'0') as usize,
+                _ => return Err(invalid("bad block length")),
+            };
+            len = len.checked_mul(10).and_then(|l| l.checked_add(digit))
+                .ok_or_else(|| invalid("block length overflow"))?;
+        }
+        if len > self.max_len {
+            return Err(invalid("block is too long"));
+        }
+        let start = 2 + digits;
+        if buf.len() < start || buf.len() - start < len {
+            return Ok(None);
+        }
+        Ok(Some((Item::Block(buf[start..(start + len)].to_vec()), start + len)))
+    }
+}
+
+impl Default for BlockCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Codec for BlockCodec {
+    type In = Item;
+    type Out = Vec<u8>;
+
+    fn decode(&mut self, buf: &[u8]) -> io::Result<Option<(Item, usize)>> {
+        let skipped = self.skipped(buf);
+        let data = &buf[skipped..];
+        let res = match data.first() {
+            None => None,
+            // Hexadecimal, octal and binary numbers are lines
+            Some(b'#') if !matches!(data.get(1), Some(b'H' | b'h' | b'Q' | b'q' | b'B' | b'b')) => {
+                self.block(data, self.scanned)?.map(|(item, n)| (item, n, data[1] != b'0'))
+            },
+            Some(_) => Self::line(data, self.scanned).map(|(item, n)| (item, n, false)),
+        };
+        match res {
+            Some((item, n, trailer)) => {
+                self.trailer = trailer;
+                self.scanned = 0;
+                Ok(Some((item, skipped + n)))
+            },
+            None => {
+                // The same data is passed again with more bytes appended, so it is not searched twice
+                self.scanned = data.len();
+                Ok(None)
+            },
+        }
+    }
+
+    fn encode(&mut self, mut msg: Vec<u8>, buf: &mut Vec<u8>) -> io::Result<()> {
+        if msg.last() != Some(&b'\n') {
+            msg.push(b'\n');
+        }
+        buf.extend_from_slice(&msg);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.trailer = false;
+        self.scanned = 0;
+    }
+}
+
+/// Streaming response decoder.
+///
+/// Input is fed in arbitrary chunks and kept until the response is complete.
+pub struct Decoder {
+    codec: BlockCodec,
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self { codec: BlockCodec::new(), buf: Vec::new() }
+    }
+
+    /// Whether the decoder is in the middle of a response.
+    pub fn is_partial(&self) -> bool {
+        self.buf.len() > self.codec.skipped(&self.buf)
+    }
+
+    /// Decodes the next chunk of the stream appending complete responses to `items`.
+    pub fn decode(&mut self, data: &[u8], items: &mut Vec<Item>) -> io::Result<()> {
+        self.buf.extend_from_slice(data);
+        let mut pos = 0;
+        while let Some((item, n)) = self.codec.decode(&self.buf[pos..])? {
+            items.push(item);
+            pos += n;
+        }
+        self.buf.drain(..pos);
+        Ok(())
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encodes the data as a definite-length block.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let len = data.len().to_string();
+    let mut buf = Vec::with_capacity(2 + len.len() + data.len());
+    buf.push(b'#');
+    buf.push(b'0' + len.len() as u8);
+    buf.extend_from_slice(len.as_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Encodes the data as an indefinite-length block including the terminating newline.
+pub fn encode_indefinite(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(3 + data.len());
+    buf.extend_from_slice(b"#0");
+    buf.extend_from_slice(data);
+    buf.push(b'\n');
+    buf
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_chunks(chunks: &[&[u8]]) -> Vec<Item> {
+        let mut dec = Decoder::new();
+        let mut items = Vec::new();
+        for chunk in chunks {
+            dec.decode(chunk, &mut items).unwrap();
+        }
+        assert!(!dec.is_partial());
+        items
+    }
+
+    #[test]
+    fn definite() {
+        let data = (0..=255).collect::<Vec<u8>>();
+        let mut stream = encode(&data);
+        assert_eq!(&stream[..5], b"#3256");
+        stream.extend_from_slice(b"\n1.5\r\n");
+
+        let items = vec![Item::Block(data), Item::Line(b"1.5".to_vec())];
+        assert_eq!(decode_chunks(&[&stream]), items);
+        let chunks = stream.iter().map(::std::slice::from_ref).collect::<Vec<_>>();
+        assert_eq!(decode_chunks(&chunks), items);
+    }
+
+    #[test]
+    fn indefinite() {
+        let mut stream = encode_indefinite(b"\x00\x01\r");
+        stream.extend_from_slice(b"#10\n");
+        assert_eq!(decode_chunks(&[&stream[..3], &stream[3..]]), vec![Item::Block(b"\x00\x01\r".to_vec()), Item::Block(Vec::new())]);
+    }
+
+    #[test]
+    fn non_decimal() {
+        let stream = b"#H1F\n#q17;#b101\r\n#3001A";
+        assert_eq!(
+            decode_chunks(&[&stream[..1], &stream[1..13], &stream[13..]]),
+            vec![Item::Line(b"#H1F".to_vec()), Item::Line(b"#q17;#b101".to_vec()), Item::Block(b"A".to_vec())],
+        );
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut dec = Decoder::new();
+        assert_matches!(dec.decode(b"#x", &mut Vec::new()), Err(_));
+        let mut dec = Decoder::new();
+        assert_matches!(dec.decode(b"#2a", &mut Vec::new()), Err(_));
+    }
+
+    #[test]
+    fn max_len() {
+        let mut codec = BlockCodec::new().max_len(3);
+        assert_eq!(codec.decode(b"#13abc").unwrap(), Some((Item::Block(b"abc".to_vec()), 6)));
+        assert_matches!(codec.decode(b"#14"), Err(ref e) if e.kind() == io::ErrorKind::InvalidData);
+        assert_matches!(codec.decode(b"#9999999999"), Err(ref e) if e.kind() == io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn scan_offset() {
+        let mut codec = BlockCodec::new();
+        assert_eq!(codec.decode(b"1.").unwrap(), None);
+        assert_eq!(codec.decode(b"1.5\n").unwrap(), Some((Item::Line(b"1.5".to_vec()), 4)));
+        assert_eq!(codec.decode(b"#0ab").unwrap(), None);
+        assert_eq!(codec.decode(b"#0ab\n").unwrap(), Some((Item::Block(b"ab".to_vec()), 5)));
+
+        // the searched part of the discarded buffer is forgotten
+        assert_eq!(codec.decode(b"abc").unwrap(), None);
+        codec.reset();
+        assert_eq!(codec.decode(b"a\n").unwrap(), Some((Item::Line(b"a".to_vec()), 2)));
+    }
+}
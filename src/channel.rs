@@ -1,18 +1,516 @@
 use std::io;
-use std::time::{Duration};
+use std::time::{Duration, Instant};
+use std::cell::{RefCell};
+use std::sync::{Arc, Weak, Mutex, MutexGuard, Condvar};
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
 use std::sync::mpsc::{self as std_chan};
 use std::error::{Error as StdError};
 use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "futures")]
+use std::pin::{Pin};
+#[cfg(feature = "futures")]
+use std::task::{self, Context, Waker};
 
 use mio;
 use mio_extras::channel::{self as mio_chan};
+#[cfg(feature = "futures")]
+use futures::{Stream, Sink};
 
 
-pub use self::mio_chan::SendError;
+pub use self::mio_chan::{SendError, TrySendError};
 pub use self::std_chan::TryRecvError;
 
-pub use self::mio_chan::{Sender, Receiver};
-pub use self::mio_chan::channel;
+
+/// Readiness state shared between the channel halves.
+///
+/// Unlike `mio_extras` channel the readiness handle is replaceable,
+/// so the receiver can be deregistered and then registered in another poll.
+struct Ctl {
+    pending: AtomicUsize,
+    senders: AtomicUsize,
+    readiness: Mutex<Option<mio::SetReadiness>>,
+    /// Task waiting for the receiver, woken up along with setting readiness.
+    #[cfg(feature = "futures")]
+    waker: Mutex<Option<Waker>>,
+    /// Maximum number of queued messages, `usize::MAX` for unbounded channel.
+    capacity: usize,
+    /// Number of queued messages.
+    len: AtomicUsize,
+    /// Set when the receiver is dropped.
+    closed: AtomicBool,
+    /// Senders drop messages instead of failing when the receiver is dropped.
+    discard: AtomicBool,
+    /// Whether there are taps, so senders don't lock them for each message otherwise.
+    tapped: AtomicBool,
+    /// Readiness of the sender, it becomes writable when the full queue gets free space.
+    space: Mutex<Option<mio::SetReadiness>>,
+    /// Notifies blocked senders about free space.
+    space_cv: Condvar,
+    /// Task waiting for free space.
+    #[cfg(feature = "futures")]
+    space_waker: Mutex<Option<Waker>>,
+}
+
+impl Ctl {
+    fn new(capacity: usize) -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            senders: AtomicUsize::new(1),
+            readiness: Mutex::new(None),
+            #[cfg(feature = "futures")]
+            waker: Mutex::new(None),
+            capacity,
+            len: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            discard: AtomicBool::new(false),
+            tapped: AtomicBool::new(false),
+            space: Mutex::new(None),
+            space_cv: Condvar::new(),
+            #[cfg(feature = "futures")]
+            space_waker: Mutex::new(None),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len.load(Ordering::Acquire) >= self.capacity
+    }
+
+    /// Takes a place in the queue if it is not full.
+    fn reserve(&self) -> bool {
+        let mut len = self.len.load(Ordering::Acquire);
+        while len < self.capacity {
+            match self.len.compare_exchange_weak(len, len + 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return true,
+                Err(actual) => len = actual,
+            }
+        }
+        false
+    }
+
+    /// Frees the place in the queue notifying senders if the queue was full.
+    fn release(&self) {
+        if self.len.fetch_sub(1, Ordering::AcqRel) == self.capacity {
+            self.notify_space();
+        }
+    }
+
+    fn notify_space(&self) {
+        let space = self.space.lock().unwrap();
+        if let Some(ref sr) = *space {
+            let _ = sr.set_readiness(mio::Ready::writable());
+        }
+        self.space_cv.notify_all();
+        #[cfg(feature = "futures")]
+        {
+            if let Some(waker) = self.space_waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Unsets the sender readiness after the queue is found full.
+    fn unset_space(&self) -> io::Result<()> {
+        match *self.space.lock().unwrap() {
+            Some(ref sr) => {
+                sr.set_readiness(mio::Ready::empty())?;
+                // The place may be freed before readiness was unset
+                if !self.is_full() || self.closed.load(Ordering::Acquire) {
+                    sr.set_readiness(mio::Ready::writable())?;
+                }
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    fn set_readiness(&self, ready: mio::Ready) -> io::Result<()> {
+        match *self.readiness.lock().unwrap() {
+            Some(ref sr) => sr.set_readiness(ready),
+            None => Ok(()),
+        }
+    }
+
+    fn inc(&self) -> io::Result<()> {
+        #[cfg(feature = "futures")]
+        self.wake();
+        if self.pending.fetch_add(1, Ordering::AcqRel) == 0 {
+            self.set_readiness(mio::Ready::readable())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn dec(&self) -> io::Result<()> {
+        let first = self.pending.load(Ordering::Acquire);
+        if first == 1 {
+            self.set_readiness(mio::Ready::empty())?;
+        }
+        let second = self.pending.fetch_sub(1, Ordering::AcqRel);
+        if first == 1 && second > 1 {
+            // Messages arrived while readiness was unset
+            self.set_readiness(mio::Ready::readable())?;
+        }
+        Ok(())
+    }
+}
+
+/// Function called with each message sent, the tap is removed when it returns `false` or panics.
+type Tap<T> = Box<dyn FnMut(&T) -> bool + Send>;
+type Taps<T> = Mutex<Vec<Tap<T>>>;
+
+/// Locks the taps ignoring poisoning, the list stays consistent as panics of taps are caught.
+fn lock_taps<T>(taps: &Taps<T>) -> MutexGuard<'_, Vec<Tap<T>>> {
+    taps.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub struct Sender<T> {
+    /// Taken on drop, so the receiver woken up by the last sender sees the disconnection.
+    tx: Option<std_chan::Sender<T>>,
+    ctl: Arc<Ctl>,
+    taps: Arc<Taps<T>>,
+    registration: RefCell<Option<mio::Registration>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends the message blocking while the bounded channel is full.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        match self.send_timeout(t, None) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Io(e)) => Err(SendError::Io(e)),
+            Err(TrySendError::Disconnected(t)) => Err(SendError::Disconnected(t)),
+            Err(TrySendError::Full(_)) => unreachable!(),
+        }
+    }
+
+    /// Sends the message if the channel is not full.
+    ///
+    /// If the sender is registered in a poll it gets writable readiness when the place is freed.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        if self.ctl.reserve() {
+            return self.push(t);
+        }
+        if self.ctl.closed.load(Ordering::Acquire) {
+            return self.disconnected(t);
+        }
+        match self.ctl.unset_space() {
+            Ok(()) => Err(TrySendError::Full(t)),
+            Err(e) => Err(TrySendError::Io(e)),
+        }
+    }
+
+    /// Sends the message waiting up to `timeout` while the channel is full, `None` means infinity.
+    pub fn send_timeout(&self, t: T, timeout: Option<Duration>) -> Result<(), TrySendError<T>> {
+        if self.ctl.reserve() {
+            return self.push(t);
+        }
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut space = self.ctl.space.lock().unwrap();
+        while !self.ctl.reserve() {
+            if self.ctl.closed.load(Ordering::Acquire) {
+                return self.disconnected(t);
+            }
+            space = match remaining(deadline) {
+                None => self.ctl.space_cv.wait(space).unwrap(),
+                Some(d) if d == Duration::from_secs(0) => return Err(TrySendError::Full(t)),
+                Some(d) => self.ctl.space_cv.wait_timeout(space, d).unwrap().0,
+            };
+        }
+        drop(space);
+        self.push(t)
+    }
+
+    /// Sends the message ignoring the channel bound.
+    ///
+    /// Used for service messages, they are rare and should neither be lost nor block the event loop.
+    pub(crate) fn force_send(&self, t: T) -> Result<(), SendError<T>> {
+        self.ctl.len.fetch_add(1, Ordering::AcqRel);
+        self.push(t).map_err(|e| match e {
+            TrySendError::Io(e) => SendError::Io(e),
+            TrySendError::Disconnected(t) | TrySendError::Full(t) => SendError::Disconnected(t),
+        })
+    }
+
+    /// Pushes the message to the place already reserved.
+    fn push(&self, t: T) -> Result<(), TrySendError<T>> {
+        if self.ctl.tapped.load(Ordering::Acquire) {
+            let mut taps = lock_taps(&self.taps);
+            taps.retain_mut(|tap| panic::catch_unwind(AssertUnwindSafe(|| tap(&t))).unwrap_or(false));
+            if taps.is_empty() {
+                self.ctl.tapped.store(false, Ordering::Release);
+            }
+        }
+        match self.tx.as_ref().expect("sender is dropped").send(t) {
+            Ok(()) => self.ctl.inc().map_err(TrySendError::Io),
+            Err(std_chan::SendError(t)) => {
+                self.ctl.len.fetch_sub(1, Ordering::AcqRel);
+                self.disconnected(t)
+            },
+        }
+    }
+
+    /// Drops the message sent after the receiver is dropped if it is allowed by `Receiver::discard_on_drop`.
+    fn disconnected(&self, t: T) -> Result<(), TrySendError<T>> {
+        if self.ctl.discard.load(Ordering::Acquire) {
+            Ok(())
+        } else {
+            Err(TrySendError::Disconnected(t))
+        }
+    }
+
+    /// Whether the bounded channel is full, unbounded one is never full.
+    pub fn is_full(&self) -> bool {
+        self.ctl.is_full()
+    }
+
+    /// Number of the sender clones alive including this one.
+    pub fn sender_count(&self) -> usize {
+        self.ctl.senders.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.ctl.senders.fetch_add(1, Ordering::Relaxed);
+        Self { tx: self.tx.clone(), ctl: self.ctl.clone(), taps: self.taps.clone(), registration: RefCell::new(None) }
+    }
+}
+
+/// The sender becomes writable when the place in the full channel is freed.
+///
+/// Only one of the sender clones could be registered at once.
+impl<T> mio::Evented for Sender<T> {
+    fn register(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> io::Result<()> {
+        let mut registration = self.registration.borrow_mut();
+        let mut space = self.ctl.space.lock().unwrap();
+        if registration.is_some() || space.is_some() {
+            return Err(io::Error::other("sender already registered"));
+        }
+        let (reg, sr) = mio::Registration::new2();
+        poll.register(&reg, token, interest, opts)?;
+        if !self.ctl.is_full() {
+            sr.set_readiness(mio::Ready::writable())?;
+        }
+        *space = Some(sr);
+        *registration = Some(reg);
+        Ok(())
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> io::Result<()> {
+        match *self.registration.borrow() {
+            Some(ref reg) => poll.reregister(reg, token, interest, opts),
+            None => Err(io::Error::other("sender not registered")),
+        }
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        match self.registration.borrow_mut().take() {
+            Some(reg) => {
+                *self.ctl.space.lock().unwrap() = None;
+                poll.deregister(&reg)
+            },
+            None => Err(io::Error::other("sender not registered")),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if self.ctl.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Wake up the receiver to let it know about disconnection
+            let _ = self.ctl.inc();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    rx: std_chan::Receiver<T>,
+    ctl: Arc<Ctl>,
+    /// Taps are owned by senders, so they are dropped along with the last sender.
+    taps: Weak<Taps<T>>,
+    registration: RefCell<Option<mio::Registration>>,
+}
+
+impl<T> Receiver<T> {
+    /// Makes senders drop messages instead of returning `Disconnected` after the receiver is dropped.
+    pub(crate) fn discard_on_drop(&self) {
+        self.ctl.discard.store(true, Ordering::Release);
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.rx.try_recv().inspect(|_| {
+            let _ = self.ctl.dec();
+            self.ctl.release();
+        })
+    }
+
+    /// Number of messages in the channel.
+    pub fn len(&self) -> usize {
+        self.ctl.len.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds the function that is called by senders with each message before it is sent to the receiver.
+    ///
+    /// The tap is removed when it returns `false` or when all senders are dropped.
+    pub fn tap<F: FnMut(&T) -> bool + Send + 'static>(&self, f: F) -> Result<(), Error> {
+        match self.taps.upgrade() {
+            Some(taps) => {
+                lock_taps(&taps).push(Box::new(f));
+                self.ctl.tapped.store(true, Ordering::Release);
+                Ok(())
+            },
+            None => Err(Error::Disconnected),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // Wake up blocked senders to let them know about disconnection
+        self.ctl.closed.store(true, Ordering::Release);
+        self.ctl.notify_space();
+    }
+}
+
+/// The sender is ready while the channel is not full.
+///
+/// Concurrent sender clones may exceed the bound by one message each, as the place is not reserved in `poll_ready`.
+#[cfg(feature = "futures")]
+impl<T> Sink<T> for Sender<T> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<Result<(), Error>> {
+        loop {
+            if self.ctl.closed.load(Ordering::Acquire) {
+                // The message is dropped by `start_send` if it is allowed
+                if self.ctl.discard.load(Ordering::Acquire) {
+                    break task::Poll::Ready(Ok(()));
+                }
+                break task::Poll::Ready(Err(Error::Disconnected));
+            }
+            if !self.ctl.is_full() {
+                break task::Poll::Ready(Ok(()));
+            }
+            let mut waker = self.ctl.space_waker.lock().unwrap();
+            if waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                break task::Poll::Pending;
+            }
+            *waker = Some(cx.waker().clone());
+            // The place may be freed before the waker is set, so check again
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Error> {
+        self.force_send(item).map_err(|e| e.into())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> task::Poll<Result<(), Error>> {
+        task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> task::Poll<Result<(), Error>> {
+        task::Poll::Ready(Ok(()))
+    }
+}
+
+/// The stream ends when all senders are dropped.
+///
+/// The receiver could be polled as a stream and registered in `mio::Poll` at the same time.
+#[cfg(feature = "futures")]
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<Option<T>> {
+        loop {
+            match self.try_recv() {
+                Ok(msg) => break task::Poll::Ready(Some(msg)),
+                Err(TryRecvError::Disconnected) => break task::Poll::Ready(None),
+                Err(TryRecvError::Empty) => {
+                    let mut waker = self.ctl.waker.lock().unwrap();
+                    if waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                        break task::Poll::Pending;
+                    }
+                    *waker = Some(cx.waker().clone());
+                    // The message may arrive before the waker is set, so check again
+                },
+            }
+        }
+    }
+}
+
+impl<T> mio::Evented for Receiver<T> {
+    fn register(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> io::Result<()> {
+        let mut registration = self.registration.borrow_mut();
+        if registration.is_some() {
+            return Err(io::Error::other("receiver already registered"));
+        }
+        let (reg, sr) = mio::Registration::new2();
+        poll.register(&reg, token, interest, opts)?;
+        let mut readiness = self.ctl.readiness.lock().unwrap();
+        if self.ctl.pending.load(Ordering::Acquire) > 0 {
+            sr.set_readiness(mio::Ready::readable())?;
+        }
+        *readiness = Some(sr);
+        *registration = Some(reg);
+        Ok(())
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> io::Result<()> {
+        match *self.registration.borrow() {
+            Some(ref reg) => poll.reregister(reg, token, interest, opts),
+            None => Err(io::Error::other("receiver not registered")),
+        }
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        match self.registration.borrow_mut().take() {
+            Some(reg) => {
+                *self.ctl.readiness.lock().unwrap() = None;
+                poll.deregister(&reg)
+            },
+            None => Err(io::Error::other("receiver not registered")),
+        }
+    }
+}
+
+/// Creates a new channel, where the `Receiver` can be registered in `mio::Poll`.
+///
+/// The `Receiver` could be registered only in one poll at once,
+/// but after deregistration it can be registered in a different one.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    with_capacity(usize::MAX)
+}
+
+/// Creates a new channel that holds at most `bound` messages.
+///
+/// `Sender::send` blocks while the channel is full, `Sender::try_send` returns `TrySendError::Full`.
+pub fn sync_channel<T>(bound: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(bound > 0, "channel bound should be non-zero");
+    with_capacity(bound)
+}
+
+fn with_capacity<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = std_chan::channel();
+    let ctl = Arc::new(Ctl::new(capacity));
+    let taps = Arc::new(Mutex::new(Vec::new()));
+    let weak_taps = Arc::downgrade(&taps);
+    (
+        Sender { tx: Some(tx), ctl: ctl.clone(), taps, registration: RefCell::new(None) },
+        Receiver { rx, ctl, taps: weak_taps, registration: RefCell::new(None) },
+    )
+}
 
 #[derive(Debug)]
 pub enum RecvError {
@@ -50,6 +548,7 @@ pub enum Error {
     Io(io::Error),
     Disconnected,
     Empty,
+    Full,
 }
 
 impl StdError for Error {
@@ -58,6 +557,7 @@ impl StdError for Error {
             Error::Io(e) => e.description(),
             Error::Disconnected => "Channel disconnected",
             Error::Empty => "Channel empty",
+            Error::Full => "Channel full",
         }
     }
 
@@ -66,6 +566,7 @@ impl StdError for Error {
             Error::Io(e) => Some(e),
             Error::Disconnected => None,
             Error::Empty => None,
+            Error::Full => None,
         }
     }
 }
@@ -85,6 +586,16 @@ impl<T> From<SendError<T>> for Error {
     }
 }
 
+impl<T> From<TrySendError<T>> for Error {
+    fn from(err: TrySendError<T>) -> Self {
+        match err {
+            TrySendError::Io(io_err) => Error::Io(io_err),
+            TrySendError::Disconnected(_) => Error::Disconnected,
+            TrySendError::Full(_) => Error::Full,
+        }
+    }
+}
+
 impl From<RecvError> for Error {
     fn from(err: RecvError) -> Self {
         match err {
@@ -125,15 +636,70 @@ impl SinglePoll {
     }
 
     pub fn wait(&mut self, timeout: Option<Duration>) -> Result<(), RecvError> {
-        self.poll.poll(&mut self.events, timeout).map_err(|e| RecvError::Io(e)).and_then(|_| {
-            match self.events.iter().next() {
-                Some(res) => {
-                    assert!(res.token() == mio::Token(0) && res.readiness().is_readable());
-                    Ok(())
-                },
-                None => Err(RecvError::Empty),
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            // Poll may wake up without any events, so we wait until the deadline
+            self.poll.poll(&mut self.events, remaining(deadline)).map_err(|e| RecvError::Io(e))?;
+            if let Some(res) = self.events.iter().next() {
+                assert!(res.token() == mio::Token(0) && res.readiness().is_readable());
+                break Ok(());
             }
-        })
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                break Err(RecvError::Empty);
+            }
+        }
+    }
+}
+
+/// Poll for waiting on many receivers at once.
+///
+/// Each receiver is registered with its own `key` which is returned by `wait` when the receiver is ready.
+pub struct MultiPoll {
+    pub poll: mio::Poll,
+    pub events: mio::Events,
+}
+
+impl MultiPoll {
+    pub fn new() -> Result<Self, Error> {
+        let poll = mio::Poll::new().map_err(Error::Io)?;
+        let events = mio::Events::with_capacity(1024);
+
+        Ok(Self { poll, events })
+    }
+
+    pub fn register<T>(&self, rx: &Receiver<T>, key: usize) -> Result<(), Error> {
+        self.poll.register(
+            rx,
+            mio::Token(key),
+            mio::Ready::readable(),
+            mio::PollOpt::edge()
+        ).map_err(Error::Io)
+    }
+
+    pub fn deregister<T>(&self, rx: &Receiver<T>) -> Result<(), Error> {
+        self.poll.deregister(rx).map_err(Error::Io)
+    }
+
+    /// Waits until at least one receiver is ready and returns the keys of ready receivers.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Vec<usize>, RecvError> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            self.poll.poll(&mut self.events, remaining(deadline)).map_err(RecvError::Io)?;
+            let mut keys = Vec::new();
+            for event in self.events.iter() {
+                assert!(event.readiness().is_readable());
+                let key = event.token().0;
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+            if !keys.is_empty() {
+                break Ok(keys);
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                break Err(RecvError::Empty);
+            }
+        }
     }
 }
 
@@ -152,24 +718,25 @@ impl<'a, T> PollReceiver<'a, T> {
     }
 
     pub fn recv(&mut self, timeout: Option<Duration>) -> Result<T, RecvError> {
-        match self.rx.try_recv() {
-            Ok(msg) => Ok(msg),
-            Err(err) => match err {
-                TryRecvError::Empty => self.wait(timeout).and_then(|_| {
-                    match self.rx.try_recv() {
-                        Ok(msg) => Ok(msg),
-                        Err(err) => match err {
-                            TryRecvError::Empty => unreachable!(),
-                            TryRecvError::Disconnected => Err(RecvError::Disconnected),
-                        }
-                    }
-                }),
-                TryRecvError::Disconnected => Err(RecvError::Disconnected),
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            match self.rx.try_recv() {
+                Ok(msg) => break Ok(msg),
+                Err(err) => match err {
+                    // Readiness event may be left from the message already received
+                    TryRecvError::Empty => self.wait(remaining(deadline))?,
+                    TryRecvError::Disconnected => break Err(RecvError::Disconnected),
+                }
             }
         }
     }
 }
 
+/// Time left until the deadline, `None` means infinity.
+pub(crate) fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|d| d.saturating_duration_since(Instant::now()))
+}
+
 
 #[cfg(test)]
 mod test {
@@ -222,7 +789,7 @@ mod test {
         let n = prx.recv(None).unwrap();
         assert_eq!(n, 42);
 
-        assert_matches!(prx.recv(None), Err(RecvError::Disconnected));
+        assert_matches!(prx.recv(Some(Duration::from_secs(1))), Err(RecvError::Disconnected));
     }
 
     #[test]
@@ -238,7 +805,7 @@ mod test {
         let n = prx.recv(None).unwrap();
         assert_eq!(n, 42);
 
-        assert_matches!(prx.recv(None), Err(RecvError::Disconnected));
+        assert_matches!(prx.recv(Some(Duration::from_secs(1))), Err(RecvError::Disconnected));
     }
 
     #[test]
@@ -257,6 +824,29 @@ mod test {
         assert_matches!(prx.wait(None), Ok(()));
     }
 
+    #[test]
+    fn send_recv_twice() {
+        let (tx, rx) = channel();
+        let mut prx = PollReceiver::new(&rx).unwrap();
+
+        tx.send(1).unwrap();
+        assert_eq!(prx.recv(Some(Duration::from_millis(100))).unwrap(), 1);
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            tx.send(2).unwrap();
+        });
+        assert_eq!(prx.recv(Some(Duration::from_millis(100))).unwrap(), 2);
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let (_tx, rx) = channel::<i32>();
+        let mut prx = PollReceiver::new(&rx).unwrap();
+
+        assert_matches!(prx.recv(Some(Duration::from_millis(10))), Err(RecvError::Empty));
+    }
+
     #[test]
     fn close_send() {
         let tx = channel().0;
@@ -272,7 +862,242 @@ mod test {
     fn multiple_polls() {
         let (_tx, rx) = channel::<i32>();
 
-        PollReceiver::new(&rx).unwrap();
+        let _prx = PollReceiver::new(&rx).unwrap();
         assert_matches!(PollReceiver::new(&rx).err(), Some(Error::Io(_)));
     }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn stream_sink() {
+        use futures::{executor, SinkExt};
+
+        let (mut tx, rx) = channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            executor::block_on(SinkExt::send(&mut tx, 1)).unwrap();
+            executor::block_on(SinkExt::send(&mut tx, 2)).unwrap();
+        });
+        assert_eq!(executor::block_on_stream(rx).collect::<Vec<i32>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn send_before_register() {
+        let (tx, rx) = channel();
+        tx.send(1).unwrap();
+
+        let mut sp = SinglePoll::new(&rx).unwrap();
+        assert_matches!(sp.wait(Some(Duration::from_millis(100))), Ok(()));
+        assert_eq!(rx.try_recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn readiness_reset() {
+        let (tx, rx) = channel();
+        let mut sp = SinglePoll::new(&rx).unwrap();
+
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_matches!(sp.wait(Some(Duration::from_millis(100))), Ok(()));
+        assert_eq!(rx.try_recv().unwrap(), 1);
+        assert_eq!(rx.try_recv().unwrap(), 2);
+        assert_matches!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_matches!(sp.wait(Some(Duration::from_millis(10))), Err(RecvError::Empty));
+
+        tx.send(3).unwrap();
+        assert_matches!(sp.wait(Some(Duration::from_millis(100))), Ok(()));
+        assert_eq!(rx.try_recv().unwrap(), 3);
+    }
+
+    #[test]
+    fn discard_on_drop() {
+        let (tx, rx) = sync_channel(1);
+        tx.send(1).unwrap();
+        drop(rx);
+        assert_matches!(tx.try_send(2), Err(TrySendError::Disconnected(2)));
+
+        let (tx, rx) = sync_channel(1);
+        tx.send(1).unwrap();
+        rx.discard_on_drop();
+        drop(rx);
+        assert_matches!(tx.try_send(2), Ok(()));
+        assert_matches!(tx.send_timeout(3, None), Ok(()));
+        assert_matches!(tx.force_send(4), Ok(()));
+    }
+
+    #[test]
+    fn last_sender_drop() {
+        let (tx, rx) = channel::<i32>();
+        let tx2 = tx.clone();
+        let mut sp = SinglePoll::new(&rx).unwrap();
+
+        drop(tx);
+        assert_matches!(sp.wait(Some(Duration::from_millis(10))), Err(RecvError::Empty));
+        assert_matches!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        thread::spawn(move || drop(tx2)).join().unwrap();
+        assert_matches!(sp.wait(Some(Duration::from_millis(100))), Ok(()));
+        assert_matches!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn last_sender_drop_while_polling() {
+        for _ in 0..100 {
+            let (tx, rx) = channel::<i32>();
+            let mut prx = PollReceiver::new(&rx).unwrap();
+
+            let thr = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(1));
+                drop(tx);
+            });
+            assert_matches!(prx.recv(Some(Duration::from_secs(1))), Err(RecvError::Disconnected));
+            thr.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn concurrent_senders() {
+        let (tx, rx) = channel();
+        let mut prx = PollReceiver::new(&rx).unwrap();
+
+        let thrs = (0..4).map(|i| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for j in 0..1000 {
+                    tx.send(i*1000 + j).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        drop(tx);
+
+        let mut msgs = Vec::new();
+        loop {
+            match prx.recv(Some(Duration::from_secs(10))) {
+                Ok(msg) => msgs.push(msg),
+                Err(RecvError::Disconnected) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        for thr in thrs {
+            thr.join().unwrap();
+        }
+        msgs.sort();
+        assert_eq!(msgs, (0..4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bounded() {
+        let (tx, rx) = sync_channel(2);
+        let poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(1);
+        poll.register(&tx, mio::Token(1), mio::Ready::writable(), mio::PollOpt::edge()).unwrap();
+        poll.poll(&mut events, Some(Duration::from_millis(100))).unwrap();
+        assert_eq!(events.iter().count(), 1);
+
+        tx.send(1).unwrap();
+        tx.try_send(2).unwrap();
+        assert!(tx.is_full());
+        assert_matches!(tx.try_send(3), Err(TrySendError::Full(3)));
+        assert_matches!(tx.send_timeout(3, Some(Duration::from_millis(10))), Err(TrySendError::Full(3)));
+        poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(events.iter().count(), 0);
+
+        let thr = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            assert_eq!(rx.try_recv().unwrap(), 1);
+            rx
+        });
+        tx.send_timeout(3, Some(Duration::from_secs(10))).unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(10))).unwrap();
+        assert_eq!(events.iter().next().unwrap().token(), mio::Token(1));
+
+        drop(thr.join().unwrap());
+        assert_matches!(tx.send(4), Err(SendError::Disconnected(4)));
+    }
+
+    #[test]
+    fn reregister_poll() {
+        let (tx, rx) = channel();
+
+        let sp = SinglePoll::new(&rx).unwrap();
+        sp.poll.deregister(&rx).unwrap();
+        drop(sp);
+
+        tx.send(42).unwrap();
+        let mut prx = PollReceiver::new(&rx).unwrap();
+        assert_eq!(prx.recv(Some(Duration::from_millis(100))).unwrap(), 42);
+    }
+
+    #[test]
+    fn tap() {
+        let (tx, rx) = channel::<i32>();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let tap_seen = seen.clone();
+        rx.tap(move |&x| {
+            let mut seen = tap_seen.lock().unwrap();
+            seen.push(x);
+            seen.len() < 2
+        }).unwrap();
+
+        let tx2 = tx.clone();
+        thread::spawn(move || tx2.send(1).unwrap()).join().unwrap();
+        tx.send(2).unwrap();
+        tx.send(3).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![1, 2]);
+        assert_eq!(rx.try_recv().unwrap(), 1);
+        assert_eq!(rx.try_recv().unwrap(), 2);
+        assert_eq!(rx.try_recv().unwrap(), 3);
+
+        drop(tx);
+        assert_matches!(rx.tap(|_| true), Err(Error::Disconnected));
+    }
+
+    #[test]
+    fn tap_panic() {
+        let (tx, rx) = channel::<i32>();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let tap_seen = seen.clone();
+        rx.tap(|&x| if x == 1 { panic!("tap panic") } else { true }).unwrap();
+        rx.tap(move |&x| {
+            tap_seen.lock().unwrap().push(x);
+            true
+        }).unwrap();
+
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![1, 2]);
+        assert_eq!(rx.try_recv().unwrap(), 1);
+        assert_eq!(rx.try_recv().unwrap(), 2);
+    }
+
+    #[test]
+    fn multi_poll() {
+        let (tx0, rx0) = channel::<i32>();
+        let (tx1, rx1) = channel::<i32>();
+        let (tx2, rx2) = channel::<i32>();
+
+        let mut mp = MultiPoll::new().unwrap();
+        mp.register(&rx0, 10).unwrap();
+        mp.register(&rx1, 11).unwrap();
+        mp.register(&rx2, 12).unwrap();
+
+        assert_matches!(mp.wait(Some(Duration::from_millis(10))), Err(RecvError::Empty));
+
+        tx0.send(0).unwrap();
+        tx2.send(2).unwrap();
+        let mut keys = mp.wait(Some(Duration::from_secs(10))).unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![10, 12]);
+        assert_eq!(rx0.try_recv().unwrap(), 0);
+        assert_eq!(rx2.try_recv().unwrap(), 2);
+
+        mp.deregister(&rx2).unwrap();
+        tx2.send(2).unwrap();
+        let thr = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            tx1.send(1).unwrap();
+        });
+        assert_eq!(mp.wait(Some(Duration::from_secs(10))).unwrap(), vec![11]);
+        assert_eq!(rx1.try_recv().unwrap(), 1);
+        thr.join().unwrap();
+    }
 }
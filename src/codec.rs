@@ -0,0 +1,200 @@
+//! Framing codecs for stream proxies.
+//!
+//! The [`Codec`] splits the byte stream into messages and encodes messages back into bytes.
+//! It is used by [`StreamProxy`] which does all buffering and readiness handling,
+//! the instrument proxies share the same buffering with their own codecs.
+//!
+//! [`Codec`]: trait.Codec.html
+//! [`StreamProxy`]: ../stream_proxy/struct.StreamProxy.html
+
+use std::io;
+
+
+pub trait Codec {
+    /// Message decoded from the stream.
+    type In;
+    /// Message encoded into the stream.
+    type Out;
+
+    /// Decodes a message from the beginning of the read buffer.
+    ///
+    /// Returns the message and the number of bytes it takes, or `None` if more data is needed.
+    fn decode(&mut self, buf: &[u8]) -> io::Result<Option<(Self::In, usize)>>;
+
+    /// Encodes the message appending it to the write buffer.
+    fn encode(&mut self, msg: Self::Out, buf: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Forgets the decoding state kept between the calls, the read buffer has been discarded.
+    fn reset(&mut self) {}
+}
+
+
+/// Messages terminated by the delimiter byte.
+///
+/// The delimiter is stripped from decoded messages and appended to encoded ones if missing.
+pub struct LineCodec {
+    delimiter: u8,
+}
+
+impl LineCodec {
+    pub fn new(delimiter: u8) -> Self {
+        Self { delimiter }
+    }
+}
+
+impl Default for LineCodec {
+    fn default() -> Self {
+        Self::new(b'\n')
+    }
+}
+
+impl Codec for LineCodec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+
+    fn decode(&mut self, buf: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>> {
+        Ok(buf.iter().position(|b| *b == self.delimiter).map(|pos| (buf[..pos].to_vec(), pos + 1)))
+    }
+
+    fn encode(&mut self, mut msg: Vec<u8>, buf: &mut Vec<u8>) -> io::Result<()> {
+        if msg.last() != Some(&self.delimiter) {
+            msg.push(self.delimiter);
+        }
+        buf.extend_from_slice(&msg);
+        Ok(())
+    }
+}
+
+
+/// Messages prefixed with their big-endian length of `width` bytes.
+pub struct LengthPrefixedCodec {
+    width: usize,
+    max_len: usize,
+}
+
+impl LengthPrefixedCodec {
+    /// Creates the codec with prefix width from 1 to 8 bytes.
+    pub fn new(width: usize) -> Self {
+        assert!((1..=8).contains(&width), "prefix width should be from 1 to 8 bytes");
+        Self { width, max_len: usize::MAX }
+    }
+
+    /// Sets the maximum length of the decoded message, longer messages are treated as invalid data.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+}
+
+impl Codec for LengthPrefixedCodec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+
+    fn decode(&mut self, buf: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>> {
+        if buf.len() < self.width {
+            return Ok(None);
+        }
+        let len = buf[..self.width].iter().fold(0u64, |len, b| (len << 8) | *b as u64);
+        if len > self.max_len as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message is too long"));
+        }
+        let end = self.width + len as usize;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        Ok(Some((buf[self.width..end].to_vec(), end)))
+    }
+
+    fn encode(&mut self, msg: Vec<u8>, buf: &mut Vec<u8>) -> io::Result<()> {
+        let len = msg.len() as u64;
+        if self.width < 8 && len >> (8*self.width) != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "message is too long for the prefix"));
+        }
+        buf.extend_from_slice(&len.to_be_bytes()[(8 - self.width)..]);
+        buf.extend_from_slice(&msg);
+        Ok(())
+    }
+}
+
+
+/// Messages of the fixed size.
+pub struct FixedCodec {
+    size: usize,
+}
+
+impl FixedCodec {
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "message size should be non-zero");
+        Self { size }
+    }
+}
+
+impl Codec for FixedCodec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+
+    fn decode(&mut self, buf: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>> {
+        if buf.len() < self.size {
+            return Ok(None);
+        }
+        Ok(Some((buf[..self.size].to_vec(), self.size)))
+    }
+
+    fn encode(&mut self, msg: Vec<u8>, buf: &mut Vec<u8>) -> io::Result<()> {
+        if msg.len() != self.size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "message size mismatch"));
+        }
+        buf.extend_from_slice(&msg);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_all<C: Codec>(codec: &mut C, mut buf: &[u8]) -> (Vec<C::In>, usize) {
+        let mut msgs = Vec::new();
+        while let Some((msg, n)) = codec.decode(buf).unwrap() {
+            msgs.push(msg);
+            buf = &buf[n..];
+        }
+        (msgs, buf.len())
+    }
+
+    #[test]
+    fn line() {
+        let mut codec = LineCodec::default();
+        let mut buf = Vec::new();
+        codec.encode(b"abc".to_vec(), &mut buf).unwrap();
+        codec.encode(b"\n".to_vec(), &mut buf).unwrap();
+        buf.extend_from_slice(b"de");
+        assert_eq!(buf, b"abc\n\nde");
+        assert_eq!(decode_all(&mut codec, &buf), (vec![b"abc".to_vec(), Vec::new()], 2));
+    }
+
+    #[test]
+    fn length_prefixed() {
+        let mut codec = LengthPrefixedCodec::new(2).max_len(0x100);
+        let mut buf = Vec::new();
+        codec.encode(b"abc".to_vec(), &mut buf).unwrap();
+        codec.encode(Vec::new(), &mut buf).unwrap();
+        assert_eq!(buf, b"\x00\x03abc\x00\x00");
+        assert_eq!(decode_all(&mut codec, &buf[..6]), (vec![b"abc".to_vec()], 1));
+        assert_eq!(decode_all(&mut codec, &buf), (vec![b"abc".to_vec(), Vec::new()], 0));
+
+        assert_matches!(codec.decode(b"\x01\x01"), Err(_));
+        assert_matches!(LengthPrefixedCodec::new(1).encode(vec![0; 0x100], &mut buf), Err(_));
+    }
+
+    #[test]
+    fn fixed() {
+        let mut codec = FixedCodec::new(2);
+        let mut buf = Vec::new();
+        codec.encode(b"ab".to_vec(), &mut buf).unwrap();
+        assert_matches!(codec.encode(b"abc".to_vec(), &mut buf), Err(_));
+        buf.push(b'c');
+        assert_eq!(decode_all(&mut codec, &buf), (vec![b"ab".to_vec()], 1));
+    }
+}
@@ -1,51 +1,272 @@
-use std::mem;
+use std::io;
 use std::thread::{self, JoinHandle};
+use std::time::{Duration};
+use std::error::{Error as StdError};
+use std::fmt;
 
-use ::channel::{channel, Sender};
-use ::proxy::{Proxy};
+use ::channel::{channel, Sender, Receiver, PollReceiver};
+use ::proxy::{Proxy, Id};
 
-use ::event_loop::{EventLoop};
+use ::event_loop::{self, EventLoop, IdGen};
 
 
 #[derive(Debug)]
-pub enum Error {}
+pub enum Error {
+    Panicked(String),
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match self {
+            Error::Panicked(_) => "Event loop thread panicked",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn StdError> {
+        match self {
+            Error::Panicked(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Panicked(msg) => write!(f, "{}: {}", (self as &dyn StdError).description(), msg),
+        }
+    }
+}
 
 pub enum Tx {
-    Attach(Box<dyn Proxy + Send>),
+    Attach(Id, Box<dyn Proxy + Send>),
+    Detach(Id, Sender<::Result<Box<dyn Proxy + Send>>>),
     Terminate,
 }
 
+/// Notifications sent by the event loop thread.
+#[derive(Debug)]
+pub enum Rx {
+    /// The event loop has been terminated on request.
+    Terminated,
+    /// The event loop has stopped because of an error, see [`Driver::status`](struct.Driver.html#method.status).
+    Failed,
+}
+
 pub struct Driver {
-    thr: Option<JoinHandle<()>>,
+    thr: Option<JoinHandle<::Result<()>>>,
     tx: Sender<Tx>,
+    /// Receives the event loop thread notifications, could be registered in a poll.
+    pub rx: Receiver<Rx>,
+    ids: IdGen,
+    result: Option<::Result<()>>,
+}
+
+/// Driver configuration.
+///
+/// ```rust
+/// use mdrv::driver::DriverBuilder;
+///
+/// let driver = DriverBuilder::new()
+///     .capacity(4096)
+///     .name("mdrv-driver")
+///     .build()
+///     .unwrap();
+/// ```
+pub struct DriverBuilder {
+    capacity: usize,
+    timeout: Option<Duration>,
+    name: Option<String>,
+    stack_size: Option<usize>,
+    affinity: Option<Vec<usize>>,
+}
+
+impl DriverBuilder {
+    pub fn new() -> Self {
+        Self {
+            capacity: 1024,
+            timeout: None,
+            name: None,
+            stack_size: None,
+            affinity: None,
+        }
+    }
+
+    /// Capacity of `mio::Events` buffer, i.e. the maximum number of events handled per one poll.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Timeout of a single poll, by default the poll waits for events infinitely.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Name of the event loop thread.
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Stack size of the event loop thread in bytes.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Set of CPUs the event loop thread is allowed to run on.
+    ///
+    /// Supported on Linux only. The affinity is applied in the event loop thread itself,
+    /// so the failure is reported through [`Driver::status`](struct.Driver.html#method.status).
+    pub fn affinity(mut self, cpus: &[usize]) -> Self {
+        self.affinity = Some(cpus.to_vec());
+        self
+    }
+
+    pub fn build(self) -> ::Result<Driver> {
+        let mut event_loop = EventLoop::with_capacity(self.capacity)?;
+        let tx = event_loop.sender();
+        let ids = event_loop.ids();
+
+        let mut builder = thread::Builder::new();
+        if let Some(name) = self.name {
+            builder = builder.name(name);
+        }
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+
+        let (timeout, affinity) = (self.timeout, self.affinity);
+        Driver::spawn(builder, tx, ids, move || {
+            if let Some(cpus) = affinity {
+                set_affinity(&cpus)?;
+            }
+            event_loop.run_forever(timeout)
+        })
+    }
+}
+
+impl Default for DriverBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    use std::mem;
+
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= 8*mem::size_of::<libc::cpu_set_t>() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU index is out of range"));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    match unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cpus: &[usize]) -> io::Result<()> {
+    Err(io::Error::other("CPU affinity is not supported on this platform"))
 }
 
 impl Driver {
+    /// Creates the driver with default configuration, see [`DriverBuilder`](struct.DriverBuilder.html).
     pub fn new() -> Result<Self, ::Error> {
-        let (tx, rx) = channel();
-        let thr = thread::spawn(move || {
-            EventLoop::new(rx).unwrap().run_forever(1024, None).unwrap();
-        });
+        DriverBuilder::new().build()
+    }
+
+    fn spawn<F>(builder: thread::Builder, tx: Sender<Tx>, ids: IdGen, f: F) -> ::Result<Self>
+    where F: FnOnce() -> ::Result<()> + Send + 'static {
+        let (ntx, nrx) = channel();
+        let thr = builder.spawn(move || {
+            let res = event_loop::catch(f).unwrap_or_else(|msg| {
+                Err(Error::Panicked(msg).into())
+            });
+            let _ = ntx.send(match res {
+                Ok(()) => Rx::Terminated,
+                Err(_) => Rx::Failed,
+            });
+            res
+        })?;
 
         Ok(Driver {
             thr: Some(thr),
             tx: tx,
+            rx: nrx,
+            ids,
+            result: None,
         })
     }
 
-    pub fn attach(&mut self, proxy: Box<dyn Proxy + Send>) -> ::Result<()> {
-        match self.tx.send(Tx::Attach(proxy)) {
-            Ok(_) => Ok(()),
+    /// Passes the proxy to the event loop and returns the id assigned to it.
+    ///
+    /// The id is allocated immediately, so it is known before the proxy is actually attached.
+    pub fn attach(&mut self, proxy: Box<dyn Proxy + Send>) -> ::Result<Id> {
+        let id = self.ids.alloc();
+        match self.tx.send(Tx::Attach(id, proxy)) {
+            Ok(_) => Ok(id),
             Err(err) => Err(::Error::Channel(err.into())),
         }
     }
+
+    /// Detaches the proxy with specified id from the event loop and returns it back.
+    ///
+    /// The call blocks until the event loop processes the request.
+    /// The proxy returned can be attached to the same or another driver again.
+    pub fn detach(&mut self, id: Id) -> ::Result<Box<dyn Proxy + Send>> {
+        let (tx, rx) = channel();
+        if let Err(err) = self.tx.send(Tx::Detach(id, tx)) {
+            return Err(::Error::Channel(err.into()));
+        }
+        let mut prx = PollReceiver::new(&rx)?;
+        match prx.recv(None) {
+            Ok(res) => res,
+            Err(err) => Err(::Error::Channel(err.into())),
+        }
+    }
+
+    /// Checks the event loop thread without blocking.
+    ///
+    /// Returns `None` while the event loop is running and the result of the event loop after it has stopped.
+    pub fn status(&mut self) -> Option<&::Result<()>> {
+        if self.result.is_none() && self.thr.as_ref().is_some_and(|thr| thr.is_finished()) {
+            self.result = Some(self.wait());
+        }
+        self.result.as_ref()
+    }
+
+    /// Terminates the event loop and waits for its thread to finish.
+    ///
+    /// Returns the error which caused the event loop to stop, if any.
+    pub fn join(mut self) -> ::Result<()> {
+        let _ = self.tx.send(Tx::Terminate);
+        self.wait()
+    }
+
+    fn wait(&mut self) -> ::Result<()> {
+        match self.result.take() {
+            Some(res) => res,
+            None => match self.thr.take() {
+                Some(thr) => thr.join().unwrap_or_else(|_| {
+                    Err(Error::Panicked(String::from("Unknown panic")).into())
+                }),
+                None => Ok(()),
+            },
+        }
+    }
 }
 
 impl Drop for Driver {
     fn drop(&mut self) {
-        self.tx.send(Tx::Terminate).unwrap();
-        let thr = mem::replace(&mut self.thr, None).unwrap();
-        thr.join().unwrap();
+        // The event loop may be already stopped, so errors are ignored
+        let _ = self.tx.send(Tx::Terminate);
+        let _ = self.wait();
     }
 }
 
@@ -54,35 +275,36 @@ impl Drop for Driver {
 mod test {
     use super::*;
 
-    use ::channel::{SinglePoll};
+    use std::time::{Duration};
+
+    use mio;
+
+    use ::channel::{RecvError};
+    use ::proxy::{Control, Eid};
+    use ::error::{IdError};
     use ::proxy_handle::{ProxyWrapper, Handle};
-    use ::dummy::{self, wait_msgs, wait_close, DummyProxy, DummyHandle};
+    use ::dummy::{self, DummyProxy, DummyHandle};
 
     fn create_dummy() -> (
         ProxyWrapper<DummyProxy, dummy::Tx, dummy::Rx>,
         Handle<DummyHandle, dummy::Tx, dummy::Rx>,
-        SinglePoll,
     ) {
-        let (p, h) = dummy::create().unwrap();
-        let sp = SinglePoll::new(&h.rx).unwrap();
-        (p, h, sp)
+        dummy::create().unwrap()
     }
 
     fn test_attach(
         h: &mut Handle<DummyHandle, dummy::Tx, dummy::Rx>,
-        sp: &mut SinglePoll,
     ) {
-        wait_msgs(h, sp, 1).unwrap();
-        assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Attached));
+        h.wait(None).unwrap();
+        assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Attached { .. }));
         assert_matches!(h.user.msgs.pop_front(), None);
     }
 
     fn test_detach(
         h: &mut Handle<DummyHandle, dummy::Tx, dummy::Rx>,
-        sp: &mut SinglePoll,
     ) {
-        wait_close(h, sp).unwrap();
-        assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Detached));
+        h.wait_close(None).unwrap();
+        assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Detached { .. }));
         assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Closed));
         assert_matches!(h.user.msgs.pop_front(), None);
     }
@@ -90,13 +312,134 @@ mod test {
     #[test]
     fn add_remove() {
         let mut drv = Driver::new().unwrap();
-        let (p, mut h, mut sp) = create_dummy();
+        let (p, mut h) = create_dummy();
 
         drv.attach(Box::new(p)).unwrap();
-        test_attach(&mut h, &mut sp);
+        test_attach(&mut h);
 
         h.close().unwrap();
-        test_detach(&mut h, &mut sp);
+        test_detach(&mut h);
+    }
+
+    #[test]
+    fn add_detach() {
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create_dummy();
+
+        let id = drv.attach(Box::new(p)).unwrap();
+        test_attach(&mut h);
+
+        let p = drv.detach(id).unwrap();
+        h.wait(None).unwrap();
+        assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Detached { .. }));
+        assert_matches!(h.user.msgs.pop_front(), None);
+
+        assert_matches!(drv.detach(id).err(), Some(::Error::Id(IdError::Missing)));
+
+        let mut odrv = Driver::new().unwrap();
+        odrv.attach(p).unwrap();
+        test_attach(&mut h);
+
+        h.close().unwrap();
+        test_detach(&mut h);
+    }
+
+    #[test]
+    fn status_join() {
+        let mut drv = Driver::new().unwrap();
+        assert_matches!(drv.status(), None);
+        let mut prx = PollReceiver::new(&drv.rx).unwrap();
+        assert_matches!(prx.recv(Some(Duration::from_millis(10))), Err(RecvError::Empty));
+        drop(prx);
+        assert_matches!(drv.join(), Ok(()));
+    }
+
+    #[test]
+    fn loop_failure() {
+        let (tx, _) = channel();
+        let mut drv = Driver::spawn(thread::Builder::new(), tx, IdGen::new(), || {
+            Err(::Error::Id(IdError::Bad))
+        }).unwrap();
+
+        let mut prx = PollReceiver::new(&drv.rx).unwrap();
+        assert_matches!(prx.recv(None), Ok(Rx::Failed));
+        drop(prx);
+
+        while drv.status().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_matches!(drv.status(), Some(Err(::Error::Id(IdError::Bad))));
+        assert_matches!(drv.attach(Box::new(create_dummy().0)), Err(::Error::Channel(_)));
+        assert_matches!(drv.join(), Err(::Error::Id(IdError::Bad)));
+    }
+
+    #[test]
+    fn loop_panic() {
+        let (tx, _) = channel();
+        let drv = Driver::spawn(thread::Builder::new(), tx, IdGen::new(), || {
+            panic!("loop panic");
+        }).unwrap();
+
+        match drv.join() {
+            Err(::Error::Driver(Error::Panicked(msg))) => assert_eq!(msg, "loop panic"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    struct NameProxy {
+        tx: Sender<Option<String>>,
+    }
+
+    impl Proxy for NameProxy {
+        fn attach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            self.tx.send(thread::current().name().map(|s| s.to_string())).map_err(|e| ::Error::Channel(e.into()))
+        }
+
+        fn detach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn process(&mut self, _ctrl: &mut Control, _readiness: mio::Ready, _eid: Eid) -> ::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let mut drv = DriverBuilder::new()
+            .capacity(16)
+            .timeout(Duration::from_millis(10))
+            .name("mdrv-test")
+            .stack_size(256*1024)
+            .build().unwrap();
+
+        let (tx, rx) = channel();
+        drv.attach(Box::new(NameProxy { tx })).unwrap();
+        let mut prx = PollReceiver::new(&rx).unwrap();
+        assert_eq!(prx.recv(None).unwrap(), Some(String::from("mdrv-test")));
+        drop(prx);
+
+        assert_matches!(drv.join(), Ok(()));
+    }
+
+    /// Any CPU the test process is allowed to run on.
+    #[cfg(target_os = "linux")]
+    fn allowed_cpu() -> usize {
+        use std::mem;
+
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        assert_eq!(unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) }, 0);
+        (0..8*mem::size_of::<libc::cpu_set_t>()).find(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) }).unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn builder_affinity() {
+        let drv = DriverBuilder::new().affinity(&[allowed_cpu()]).build().unwrap();
+        assert_matches!(drv.join(), Ok(()));
+
+        let drv = DriverBuilder::new().affinity(&[1 << 20]).build().unwrap();
+        assert_matches!(drv.join(), Err(::Error::Io(_)));
     }
 
     #[test]
@@ -104,26 +447,32 @@ mod test {
         let mut drv = Driver::new().unwrap();
         let phs = (0..16).map(|_| create_dummy());
         let mut hs = Vec::new();
+        let mut ids = Vec::new();
 
-        for (p, h, sp) in phs {
-            drv.attach(Box::new(p)).unwrap();
-            hs.push((h, sp));
+        for (p, h) in phs {
+            ids.push(drv.attach(Box::new(p)).unwrap());
+            hs.push(h);
         }
 
-        for (h, sp) in hs.iter_mut() {
-            test_attach(h, sp);
+        let mut uids = ids.clone();
+        uids.sort();
+        uids.dedup();
+        assert_eq!(uids.len(), ids.len());
+
+        for h in hs.iter_mut() {
+            test_attach(h);
         }
 
-        for (h, _) in hs.iter_mut() {
+        for h in hs.iter_mut() {
             h.close().unwrap();
         }
 
-        for (h, sp) in hs.iter_mut() {
-            test_detach(h, sp);
+        for h in hs.iter_mut() {
+            test_detach(h);
         }
 
-        for (h, _) in hs.iter() {
-            assert_eq!(h.is_closed(), true);
+        for h in hs.iter() {
+            assert!(h.is_closed());
         }
     }
 }
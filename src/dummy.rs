@@ -1,8 +1,7 @@
 use std::collections::{VecDeque};
 
-use ::channel::{SinglePoll};
-use ::proxy::{self, Proxy, Control, Eid};
-use ::proxy_handle::{self, ProxyWrapper, Handle, UserProxy, UserHandle};
+use ::proxy::{Proxy, Control, Eid};
+use ::proxy_handle::{self, UserProxy, UserHandle};
 
 pub use proxy_handle::{Tx, Rx};
 
@@ -53,36 +52,6 @@ impl UserHandle<Tx, Rx> for DummyHandle {
     }
 }
 
-pub fn create() -> ::Result<(ProxyWrapper<DummyProxy, Tx, Rx>, Handle<DummyHandle, Tx, Rx>)> {
+pub fn create() -> ::Result<proxy_handle::Pair<DummyProxy, DummyHandle, Tx, Rx>> {
     proxy_handle::create(DummyProxy::new(), DummyHandle::new())
 }
-
-pub fn wait_msgs(h: &mut Handle<DummyHandle, Tx, Rx>, sp: &mut SinglePoll, n: usize) -> ::Result<()> {
-    let ns = h.user.msgs.len();
-    loop {
-        if let Err(e) = sp.wait(None) {
-            break Err(::Error::Channel(e.into()));
-        }
-        if let Err(e) = h.process() {
-            break Err(e);
-        }
-        if h.user.msgs.len() - ns >= n {
-            break Ok(());
-        }
-    }
-}
-
-pub fn wait_close(h: &mut Handle<DummyHandle, Tx, Rx>, sp: &mut SinglePoll) -> ::Result<()> {
-    loop {
-        if let Err(e) = sp.wait(None) {
-            break Err(::Error::Channel(e.into()));
-        }
-        match h.process() {
-            Ok(()) => continue,
-            Err(err) => match err {
-                ::Error::Proxy(proxy::Error::Closed) => break Ok(()),
-                other => break Err(other),
-            }
-        }
-    }
-}
\ No newline at end of file
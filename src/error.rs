@@ -4,6 +4,7 @@ use std::io;
 
 use ::channel;
 use ::proxy;
+use ::driver;
 
 
 #[derive(Debug)]
@@ -12,6 +13,8 @@ pub enum Error {
     Id(IdError),
     Channel(channel::Error),
     Proxy(proxy::Error),
+    Driver(driver::Error),
+    Timeout,
 }
 
 impl error::Error for Error {
@@ -21,6 +24,8 @@ impl error::Error for Error {
             Error::Id(e) => e.description(),
             Error::Channel(e) => e.description(),
             Error::Proxy(e) => e.description(),
+            Error::Driver(e) => e.description(),
+            Error::Timeout => "Operation timed out",
         }
     }
 
@@ -30,6 +35,8 @@ impl error::Error for Error {
             Error::Id(e) => Some(e),
             Error::Channel(e) => Some(e),
             Error::Proxy(e) => Some(e),
+            Error::Driver(e) => Some(e),
+            Error::Timeout => None,
         }
     }
 }
@@ -90,3 +97,9 @@ impl From<proxy::Error> for Error {
         Error::Proxy(err)
     }
 }
+
+impl From<driver::Error> for Error {
+    fn from(err: driver::Error) -> Error {
+        Error::Driver(err)
+    }
+}
@@ -1,20 +1,51 @@
-use std::cell::{Cell};
+//! Event loop that could be run in the caller's thread.
+//!
+//! ```rust
+//! use std::time::{Duration};
+//! use mdrv::{dummy, event_loop::EventLoop};
+//!
+//! let mut event_loop = EventLoop::new().unwrap();
+//! let (proxy, mut handle) = dummy::create().unwrap();
+//!
+//! // the proxy is attached immediately
+//! event_loop.attach(Box::new(proxy)).unwrap();
+//!
+//! handle.close().unwrap();
+//! while !handle.is_closed() {
+//!     // interleave event processing with other work
+//!     event_loop.run_once(Some(Duration::from_millis(10))).unwrap();
+//!     let _ = handle.process();
+//! }
+//! ```
+
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::cell::{Cell, RefCell};
 use std::time::{Duration};
 use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use mio;
 
 use ::error::{IdError};
-use ::channel::{self, Receiver, TryRecvError};
-use ::proxy::{self, Id, Eid, Proxy, Control};
+use ::channel::{self, Sender, Receiver, TryRecvError};
+use ::proxy::{self, Id, Proxy, Control, ErrorPolicy};
+use ::timer::{Timers};
+use ::token::{self, Tokens};
 use ::driver::{Tx as Rx};
 
 
+/// Channel to send the detached proxy back to the requester.
+type DetachReply = Sender<::Result<Box<dyn Proxy + Send>>>;
+
 struct Context {
     events: Cell<Option<mio::Events>>,
 
-    to_add: Vec<Box<dyn Proxy + Send>>,
+    to_add: Vec<(Id, Box<dyn Proxy + Send>)>,
     to_del: BTreeSet<Id>,
+    to_restart: BTreeSet<Id>,
+    to_detach: Vec<(Id, DetachReply)>,
 
     exit: bool,
 }
@@ -25,6 +56,8 @@ impl Context {
             events: Cell::new(Some(mio::Events::with_capacity(capacity))),
             to_add: Vec::new(),
             to_del: BTreeSet::new(),
+            to_restart: BTreeSet::new(),
+            to_detach: Vec::new(),
             exit: false,
         }
     }
@@ -37,8 +70,8 @@ impl Context {
         }
     }
 
-    fn add(&mut self, proxy: Box<dyn Proxy + Send>) -> ::Result<()> {
-        self.to_add.push(proxy);
+    fn add(&mut self, id: Id, proxy: Box<dyn Proxy + Send>) -> ::Result<()> {
+        self.to_add.push((id, proxy));
         Ok(())
     }
 
@@ -46,84 +79,260 @@ impl Context {
         self.to_del.insert(id);
         Ok(())
     }
+
+    fn restart(&mut self, id: Id) -> ::Result<()> {
+        self.to_restart.insert(id);
+        Ok(())
+    }
+
+    fn take(&mut self, id: Id, tx: DetachReply) -> ::Result<()> {
+        self.to_detach.push((id, tx));
+        Ok(())
+    }
+}
+
+/// Runs proxy code and catches the panic if it occurs.
+pub(crate) fn catch<R, F: FnOnce() -> R>(f: F) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg.to_string()
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.clone()
+        } else {
+            String::from("Unknown panic")
+        }
+    })
+}
+
+/// Notifies the panicked proxy and drops it.
+///
+/// The proxy state may be broken, so further panics are suppressed too.
+fn discard(mut proxy: Box<dyn Proxy + Send>, msg: String) {
+    let _ = catch(|| proxy.panicked(msg));
+    let _ = catch(move || drop(proxy));
+}
+
+/// Ignores the error of the proxy that is gone, e.g. after a panic.
+///
+/// Errors of the proxy itself are reported by `Proxy::error`, so others are the event loop errors.
+fn skip_missing(res: ::Result<()>) -> ::Result<()> {
+    match res {
+        Err(::Error::Id(IdError::Missing)) => Ok(()),
+        other => other,
+    }
+}
+
+/// Shared allocator of proxy ids.
+///
+/// Ids are taken at submission time and are never reused during the event loop lifetime.
+#[derive(Clone)]
+pub struct IdGen {
+    next: Arc<AtomicUsize>,
+}
+
+impl IdGen {
+    pub(crate) fn new() -> Self {
+        Self { next: Arc::new(AtomicUsize::new(1)) }
+    }
+
+    pub fn alloc(&self) -> Id {
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
 }
 
+/// Event loop which owns proxies and dispatches events to them.
+///
+/// Usually it runs in a separate thread maintained by the [`Driver`](../driver/struct.Driver.html),
+/// but also could be driven manually from the caller's thread.
 pub struct EventLoop {
+    ctx: Option<Context>,
+    tx: Sender<Rx>,
     rx: Receiver<Rx>,
     proxies: BTreeMap<Id, Cell<Option<Box<dyn Proxy + Send>>>>,
     poll: mio::Poll,
+    timers: RefCell<Timers>,
+    tokens: RefCell<Tokens>,
+    ids: IdGen,
 }
 
 impl EventLoop {
-    pub fn new(rx: Receiver<Rx>) -> ::Result<Self> {
+    pub fn new() -> ::Result<Self> {
+        Self::with_capacity(1024)
+    }
+
+    /// Creates the event loop, `capacity` is the maximum number of events handled per one poll.
+    pub fn with_capacity(capacity: usize) -> ::Result<Self> {
+        let (tx, rx) = channel::channel();
         let poll = mio::Poll::new().map_err(|e| ::Error::Io(e))?;
         poll.register(
-            &rx, mio::Token(0),
+            &rx, token::LOOP,
             mio::Ready::readable(),
             mio::PollOpt::edge()
         ).map_err(|e| ::Error::Io(e))?;
+        let timers = Timers::new();
+        poll.register(
+            &timers, token::TIMER,
+            mio::Ready::readable(),
+            mio::PollOpt::edge()
+        ).map_err(::Error::Io)?;
         Ok(EventLoop {
+            ctx: Some(Context::new(capacity)),
+            tx,
             rx,
             proxies: BTreeMap::new(),
             poll,
+            timers: RefCell::new(timers),
+            tokens: RefCell::new(Tokens::new()),
+            ids: IdGen::new(),
         })
     }
 
-    fn next_id(&self) -> Id {
-        if self.proxies.is_empty() {
-            1
-        } else {
-            *self.proxies.range(..).next_back().unwrap().0 + 1
-        }
+    /// Returns the sender which allows to control the event loop from other threads.
+    pub fn sender(&self) -> Sender<Rx> {
+        self.tx.clone()
+    }
+
+    pub fn ids(&self) -> IdGen {
+        self.ids.clone()
     }
 
     fn control(&self, id: Id) -> Control {
-        Control::new(id, &self.poll)
+        Control::new(id, &self.poll, &self.timers, &self.tokens)
     }
 
-    fn attach(&mut self, id: Id, mut proxy: Box<dyn Proxy + Send>) -> ::Result<()> {
-        if !self.proxies.contains_key(&id) {
-            proxy.attach(&self.control(id)).and_then(|_| {
-                match self.proxies.insert(id, Cell::new(Some(proxy))) {
-                    Some(_) => unreachable!(),
-                    None => Ok(()),
-                }
+    /// Frees timers and tokens of the proxy.
+    fn release(&self, id: Id) {
+        self.timers.borrow_mut().cancel_all(id);
+        self.tokens.borrow_mut().remove(id);
+    }
+
+    /// Reports the error to the proxy and tells what to do with the proxy next.
+    fn fail(proxy: &mut Box<dyn Proxy + Send>, ctrl: &Control, err: ::Error) -> ErrorPolicy {
+        let policy = proxy.error_policy();
+        proxy.error(ctrl, err);
+        policy
+    }
+
+    /// Attaches the proxy immediately and returns its id.
+    ///
+    /// As in the [`Driver`](../driver/struct.Driver.html) the error of `Proxy::attach`
+    /// is reported to the proxy itself and then the proxy is dropped.
+    pub fn attach(&mut self, proxy: Box<dyn Proxy + Send>) -> ::Result<Id> {
+        let id = self.ids.alloc();
+        self.insert(id, proxy);
+        Ok(id)
+    }
+
+    /// Attaches the proxy. If the attachment fails the proxy is notified and dropped.
+    fn insert(&mut self, id: Id, mut proxy: Box<dyn Proxy + Send>) {
+        let ctrl = self.control(id);
+        let res = catch(|| {
+            let res = if !self.proxies.contains_key(&id) {
+                proxy.attach(&ctrl)
+            } else {
+                Err(IdError::Present.into())
+            };
+            res.map_err(|e| {
+                Self::fail(&mut proxy, &ctrl, e);
             })
-        } else {
-            Err(IdError::Present.into())
+        });
+        match res {
+            Ok(Ok(())) => assert!(self.proxies.insert(id, Cell::new(Some(proxy))).is_none()),
+            Ok(Err(())) => self.release(id),
+            Err(msg) => {
+                self.release(id);
+                discard(proxy, msg);
+            },
         }
     }
 
-    fn detach(&mut self, id: Id) -> ::Result<Box<dyn Proxy + Send>> {
-        match self.proxies.remove(&id) {
-            Some(proxy_cell) => {
-                let mut proxy = proxy_cell.into_inner().unwrap();
-                match proxy.detach(&self.control(id)) {
-                    Ok(()) => Ok(proxy),
-                    Err(e) => Err(e),
+    /// Detaches the proxy with specified id and returns it back.
+    pub fn detach(&mut self, id: Id) -> ::Result<Box<dyn Proxy + Send>> {
+        self.release(id);
+        match self.proxies.remove(&id).and_then(|cell| cell.into_inner()) {
+            Some(mut proxy) => {
+                match catch(|| proxy.detach(&self.control(id))) {
+                    Ok(Ok(())) => Ok(proxy),
+                    Ok(Err(e)) => Err(e),
+                    Err(msg) => {
+                        discard(proxy, msg);
+                        Err(proxy::Error::Panicked.into())
+                    },
                 }
             },
             None => Err(IdError::Missing.into()),
         }
     }
 
-    fn process_proxy(&self, ctx: &mut Context, ready: mio::Ready, id: Id, eid: Eid) -> ::Result<()> {
-        match self.proxies.get(&id) {
-            Some(ref proxy_cell) => {
-                let mut proxy = proxy_cell.take().unwrap();
+    /// Detaches the proxy and reports detachment error to the proxy itself.
+    fn remove(&mut self, id: Id) -> Option<Box<dyn Proxy + Send>> {
+        self.release(id);
+        let mut proxy = self.proxies.remove(&id)?.into_inner()?;
+        let ctrl = self.control(id);
+        let res = catch(|| {
+            if let Err(e) = proxy.detach(&ctrl) {
+                Self::fail(&mut proxy, &ctrl, e);
+            }
+        });
+        match res {
+            Ok(()) => Some(proxy),
+            Err(msg) => {
+                discard(proxy, msg);
+                None
+            },
+        }
+    }
+
+    /// Calls the proxy with `f` handling its errors and panics.
+    fn process_proxy<F>(&self, ctx: &mut Context, id: Id, f: F) -> ::Result<()>
+    where F: FnOnce(&mut Box<dyn Proxy + Send>, &mut Control) -> ::Result<()> {
+        // The cell is empty if the proxy has panicked earlier
+        match self.proxies.get(&id).map(|cell| (cell, cell.take())) {
+            Some((proxy_cell, Some(mut proxy))) => {
                 let mut ctrl = self.control(id);
-                let res = proxy.process(&mut ctrl, ready, eid).and_then(|_| {
-                    ctx.apply(&ctrl)
+                let res = catch(|| {
+                    match f(&mut proxy, &mut ctrl) {
+                        Ok(()) => None,
+                        Err(e) => Some(Self::fail(&mut proxy, &ctrl, e)),
+                    }
                 });
-                proxy_cell.set(Some(proxy));
-                res
+                match res {
+                    Ok(policy) => {
+                        proxy_cell.set(Some(proxy));
+                        match policy {
+                            None | Some(ErrorPolicy::Ignore) => ctx.apply(&ctrl),
+                            Some(ErrorPolicy::Detach) => ctx.del(id),
+                            Some(ErrorPolicy::Restart) => ctx.restart(id),
+                        }
+                    },
+                    Err(msg) => {
+                        discard(proxy, msg);
+                        ctx.del(id)
+                    },
+                }
             },
-            None => Err(IdError::Missing.into()),
+            _ => Err(IdError::Missing.into()),
         }
     }
     
-    fn process_self(&self, ctx: &mut Context, ready: mio::Ready, eid: Eid) -> ::Result<()> {
-        assert_eq!(eid, 0);
+    fn process_timers(&self, ctx: &mut Context) -> ::Result<()> {
+        let mut result = Ok(());
+        loop {
+            // Timers must not be borrowed while the proxy is processed
+            let next = self.timers.borrow_mut().poll();
+            match next {
+                Some((id, tid)) => {
+                    // Proxy could be removed while the timer was pending
+                    skip_missing(self.process_proxy(ctx, id, |p, ctrl| p.timeout(ctrl, tid))).unwrap_or_else(|e| {
+                        result = Err(e);
+                    });
+                },
+                None => break result,
+            }
+        }
+    }
+
+    fn process_self(&self, ctx: &mut Context, ready: mio::Ready) -> ::Result<()> {
         assert!(ready.is_readable());
         loop {
             match self.rx.try_recv() {
@@ -131,8 +340,14 @@ impl EventLoop {
                     Rx::Terminate => {
                         ctx.exit = true;
                     },
-                    Rx::Attach(proxy) => {
-                        match ctx.add(proxy) {
+                    Rx::Attach(id, proxy) => {
+                        match ctx.add(id, proxy) {
+                            Ok(_) => continue,
+                            Err(err) => break Err(err),
+                        }
+                    },
+                    Rx::Detach(id, tx) => {
+                        match ctx.take(id, tx) {
                             Ok(_) => continue,
                             Err(err) => break Err(err),
                         }
@@ -150,70 +365,91 @@ impl EventLoop {
         let events = ctx.events.take().unwrap();
         let mut result = Ok(());
         for event in events.iter() {
-            let token = event.token();
-            let (id, eid) = proxy::decode_ids(token);
             let ready = event.readiness();
-            match id {
-                0 => self.process_self(ctx, ready, eid),
-                proxy_id => self.process_proxy(ctx, ready, proxy_id, eid),
-            }.unwrap_or_else(|e| {
-                result = Err(e);
-            });
+            match event.token() {
+                token::LOOP => self.process_self(ctx, ready).unwrap_or_else(|e| {
+                    result = Err(e);
+                }),
+                token::TIMER => self.process_timers(ctx).unwrap_or_else(|e| {
+                    result = Err(e);
+                }),
+                other => {
+                    let ids = self.tokens.borrow().get(other);
+                    // Events of already removed proxies are skipped
+                    if let Some((id, eid)) = ids {
+                        skip_missing(self.process_proxy(ctx, id, |p, ctrl| p.process(ctrl, ready, eid))).unwrap_or_else(|e| {
+                            result = Err(e);
+                        });
+                    }
+                },
+            }
         }
         ctx.events.set(Some(events));
         result
     }
 
-    fn commit(&mut self, ctx: &mut Context) -> ::Result<()> {
-        let mut result = Ok(());
+    fn commit(&mut self, ctx: &mut Context) {
         for id in ctx.to_del.iter() {
-            self.detach(*id).map(|_| ()).unwrap_or_else(|e| {
-                result = Err(e);
-            });
+            self.remove(*id);
         }
         ctx.to_del.clear();
 
-        let mut id_cnt = self.next_id();
-        for proxy in ctx.to_add.drain(..) {
-            let id = id_cnt;
-            id_cnt += 1;
-            self.attach(id, proxy).unwrap_or_else(|e| {
-                result = Err(e);
-            });
+        let to_restart = mem::take(&mut ctx.to_restart);
+        for id in to_restart.into_iter() {
+            if let Some(proxy) = self.remove(id) {
+                self.insert(id, proxy);
+            }
         }
 
-        Ok(())
+        for (id, proxy) in ctx.to_add.drain(..) {
+            self.insert(id, proxy);
+        }
+
+        for (id, tx) in ctx.to_detach.drain(..) {
+            // The requester may have gone away, then the proxy is simply dropped
+            let _ = tx.send(self.detach(id));
+        }
     }
 
-    fn run_once(&mut self, ctx: &mut Context, timeout: Option<Duration>) -> ::Result<()> {
+    fn run_once_ctx(&mut self, ctx: &mut Context, timeout: Option<Duration>) -> ::Result<()> {
         self.poll.poll(ctx.events.get_mut().as_mut().unwrap(), timeout).map_err(|e| ::Error::Io(e))?;
 
-        self.process(ctx).unwrap();
+        // Changes made before the error must be applied anyway
+        let res = self.process(ctx);
 
-        self.commit(ctx).unwrap();
+        self.commit(ctx);
 
-        Ok(())
+        res
     }
 
-    pub fn run_forever(&mut self, capacity: usize, timeout: Option<Duration>) -> ::Result<()> {
-        let mut ctx = Context::new(capacity);
-        while !ctx.exit {
-            self.run_once(&mut ctx, timeout)?;
+    /// Waits for events at most `timeout` and processes them.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> ::Result<()> {
+        let mut ctx = self.ctx.take().unwrap();
+        let res = self.run_once_ctx(&mut ctx, timeout);
+        self.ctx = Some(ctx);
+        res
+    }
+
+    /// Runs the event loop until `Terminate` is received.
+    pub fn run_forever(&mut self, timeout: Option<Duration>) -> ::Result<()> {
+        while !self.is_terminated() {
+            self.run_once(timeout)?;
         }
         Ok(())
     }
+
+    /// Tells whether the event loop has received `Terminate`.
+    pub fn is_terminated(&self) -> bool {
+        self.ctx.as_ref().unwrap().exit
+    }
 }
 
 impl Drop for EventLoop {
     fn drop(&mut self) {
-        let mut res = Ok(());
-        for (id, proxy_cell) in self.proxies.iter() {
-            let mut proxy = proxy_cell.take().unwrap();
-            if let Err(e) = proxy.detach(&self.control(*id)) {
-                res = Err(e);
-            }
+        let ids = self.proxies.keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            self.remove(id);
         }
-        res.unwrap();
     }
 }
 
@@ -221,22 +457,26 @@ impl Drop for EventLoop {
 mod test {
     use super::*;
 
+    use std::io;
     use std::thread;
     use std::sync::{Arc, Mutex};
 
-    use ::channel::{channel, Sender, SendError, SinglePoll};
+    use ::channel::{channel, Sender, SendError, PollReceiver, RecvError};
+    use ::proxy::{Eid, TimerId};
+
+    use std::collections::{VecDeque};
 
-    use ::dummy::{self, wait_msgs, wait_close};
+    use ::proxy_handle::{self, UserProxy};
+    use ::dummy::{self, DummyHandle};
 
 
     fn loop_wrap<F: FnOnce(Arc<Mutex<EventLoop>>, &Sender<Rx>)>(f: F) {
-        let (tx, rx) = channel();
-        let el = Arc::new(Mutex::new(EventLoop::new(rx).unwrap()));
+        let el = Arc::new(Mutex::new(EventLoop::with_capacity(16).unwrap()));
+        let tx = el.lock().unwrap().sender();
         let elc = el.clone();
         let jh = thread::spawn(move || {
-            let mut ctx = Context::new(16);
-            while !ctx.exit {
-                elc.lock().unwrap().run_once(&mut ctx, Some(Duration::from_millis(10))).unwrap();
+            while !elc.lock().unwrap().is_terminated() {
+                elc.lock().unwrap().run_once(Some(Duration::from_millis(10))).unwrap();
                 thread::sleep(Duration::from_millis(1));
             }
         });
@@ -253,6 +493,12 @@ mod test {
         jh.join().unwrap();
     }
 
+    #[test]
+    fn skip_missing_proxy() {
+        assert_matches!(skip_missing(Err(IdError::Missing.into())), Ok(()));
+        assert_matches!(skip_missing(Err(IdError::Present.into())), Err(::Error::Id(IdError::Present)));
+    }
+
     #[test]
     fn run() {
         loop_wrap(|_, _| {});
@@ -269,23 +515,389 @@ mod test {
     fn attach_detach() {
         loop_wrap(|el, tx| {
             let (p, mut h) = dummy::create().unwrap();
-            let mut sp = SinglePoll::new(&h.rx).unwrap();
 
-            tx.send(Rx::Attach(Box::new(p))).unwrap();
+            let id = el.lock().unwrap().ids().alloc();
+            tx.send(Rx::Attach(id, Box::new(p))).unwrap();
 
-            wait_msgs(&mut h, &mut sp, 1).unwrap();
-            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Attached));
+            h.wait(None).unwrap();
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Attached { .. }));
             assert_matches!(h.user.msgs.pop_front(), None);
             assert_eq!(el.lock().unwrap().proxies.len(), 1);
+            assert!(el.lock().unwrap().proxies.contains_key(&id));
 
             h.close().unwrap();
 
-            wait_close(&mut h, &mut sp).unwrap();
-            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Detached));
+            h.wait_close(None).unwrap();
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Detached { .. }));
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Closed));
+            assert_matches!(h.user.msgs.pop_front(), None);
+            assert!(h.is_closed());
+            assert_eq!(el.lock().unwrap().proxies.len(), 0);
+        });
+    }
+
+    #[test]
+    fn direct() {
+        let mut el = EventLoop::new().unwrap();
+        let (p, mut h) = dummy::create().unwrap();
+
+        let id = el.attach(Box::new(p)).unwrap();
+        h.process().unwrap();
+        assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Attached { .. }));
+
+        h.close().unwrap();
+        while el.proxies.contains_key(&id) {
+            el.run_once(Some(Duration::from_millis(10))).unwrap();
+        }
+        assert_matches!(h.process(), Err(::Error::Proxy(proxy::Error::Closed)));
+        assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Detached { .. }));
+        assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Closed));
+
+        el.sender().send(Rx::Terminate).unwrap();
+        el.run_forever(None).unwrap();
+        assert!(el.is_terminated());
+    }
+
+    #[test]
+    fn attach_take() {
+        loop_wrap(|el, tx| {
+            let (p, mut h) = dummy::create().unwrap();
+
+            let id = el.lock().unwrap().ids().alloc();
+            tx.send(Rx::Attach(id, Box::new(p))).unwrap();
+            h.wait(None).unwrap();
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Attached { .. }));
+
+            let (rtx, rrx) = channel();
+            tx.send(Rx::Detach(id, rtx)).unwrap();
+            let p = PollReceiver::new(&rrx).unwrap().recv(None).unwrap().unwrap();
+            assert_eq!(el.lock().unwrap().proxies.len(), 0);
+
+            h.wait(None).unwrap();
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Detached { .. }));
+            assert_matches!(h.user.msgs.pop_front(), None);
+
+            drop(p);
+            h.wait_close(None).unwrap();
             assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Closed));
             assert_matches!(h.user.msgs.pop_front(), None);
-            assert_eq!(h.is_closed(), true);
+        });
+    }
+
+    struct FailProxy {
+        reg: mio::Registration,
+        policy: ErrorPolicy,
+        log: Sender<&'static str>,
+    }
+
+    impl Proxy for FailProxy {
+        fn attach(&mut self, ctrl: &Control) -> ::Result<()> {
+            ctrl.register(&self.reg, 1, mio::Ready::readable(), mio::PollOpt::edge())?;
+            let _ = self.log.send("attach");
+            Ok(())
+        }
+
+        fn detach(&mut self, ctrl: &Control) -> ::Result<()> {
+            ctrl.deregister_eid(&self.reg, 1)?;
+            let _ = self.log.send("detach");
+            Ok(())
+        }
+
+        fn process(&mut self, _ctrl: &mut Control, _readiness: mio::Ready, _eid: Eid) -> ::Result<()> {
+            Err(io::Error::other("fail").into())
+        }
+
+        fn error(&mut self, _ctrl: &Control, _err: ::Error) {
+            let _ = self.log.send("error");
+        }
+
+        fn error_policy(&self) -> ErrorPolicy {
+            self.policy
+        }
+    }
+
+    impl Drop for FailProxy {
+        fn drop(&mut self) {
+            let _ = self.log.send("drop");
+        }
+    }
+
+    fn fail_with(policy: ErrorPolicy, log: &[&str]) {
+        loop_wrap(|el, tx| {
+            let (reg, sr) = mio::Registration::new2();
+            let (ltx, lrx) = channel();
+            let mut prx = PollReceiver::new(&lrx).unwrap();
+            let (p, mut h) = dummy::create().unwrap();
+
+            let ids = el.lock().unwrap().ids();
+            let fp = FailProxy { reg, policy, log: ltx };
+            tx.send(Rx::Attach(ids.alloc(), Box::new(fp))).unwrap();
+            tx.send(Rx::Attach(ids.alloc(), Box::new(p))).unwrap();
+            h.wait(None).unwrap();
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Attached { .. }));
+            assert_eq!(prx.recv(Some(Duration::from_secs(1))).unwrap(), "attach");
+
+            sr.set_readiness(mio::Ready::readable()).unwrap();
+            for entry in log {
+                assert_eq!(prx.recv(Some(Duration::from_secs(1))).unwrap(), *entry);
+            }
+
+            // other proxies are still alive
+            h.close().unwrap();
+            h.wait_close(None).unwrap();
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Detached { .. }));
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Closed));
+        });
+    }
+
+    #[test]
+    fn fail_detach() {
+        fail_with(ErrorPolicy::Detach, &["error", "detach", "drop"]);
+    }
+
+    #[test]
+    fn fail_ignore() {
+        fail_with(ErrorPolicy::Ignore, &["error"]);
+    }
+
+    #[test]
+    fn fail_restart() {
+        fail_with(ErrorPolicy::Restart, &["error", "detach", "attach"]);
+    }
+
+    struct PanicProxy {}
+
+    impl Proxy for PanicProxy {
+        fn attach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn detach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn process(&mut self, _ctrl: &mut Control, _readiness: mio::Ready, _eid: Eid) -> ::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl UserProxy<dummy::Tx, dummy::Rx> for PanicProxy {
+        fn process_channel(&mut self, _ctrl: &mut Control, _msg: dummy::Tx) -> ::Result<()> {
+            panic!("proxy panic");
+        }
+    }
+
+    #[test]
+    fn panic() {
+        loop_wrap(|el, tx| {
+            let (pp, mut ph) = proxy_handle::create(PanicProxy {}, DummyHandle { msgs: VecDeque::new() }).unwrap();
+            let (p, mut h) = dummy::create().unwrap();
+
+            let ids = el.lock().unwrap().ids();
+            tx.send(Rx::Attach(ids.alloc(), Box::new(pp))).unwrap();
+            tx.send(Rx::Attach(ids.alloc(), Box::new(p))).unwrap();
+            ph.wait(None).unwrap();
+            assert_matches!(ph.user.msgs.pop_front(), Some(dummy::Rx::Attached { .. }));
+            h.wait(None).unwrap();
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Attached { .. }));
+
+            ph.close().unwrap();
+            ph.wait_close(None).unwrap();
+            match ph.user.msgs.pop_front() {
+                Some(dummy::Rx::Panicked(msg)) => assert_eq!(msg, "proxy panic"),
+                other => panic!("{:?}", other),
+            }
+            assert_matches!(ph.user.msgs.pop_front(), Some(dummy::Rx::Closed));
+            assert_matches!(ph.user.msgs.pop_front(), None);
+
+            h.close().unwrap();
+            h.wait_close(None).unwrap();
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Detached { .. }));
+            assert_matches!(h.user.msgs.pop_front(), Some(dummy::Rx::Closed));
             assert_eq!(el.lock().unwrap().proxies.len(), 0);
         });
     }
-}
\ No newline at end of file
+
+    struct TimerProxy {
+        log: Sender<&'static str>,
+        single: Option<TimerId>,
+        interval: Option<TimerId>,
+        count: usize,
+    }
+
+    impl Proxy for TimerProxy {
+        fn attach(&mut self, ctrl: &Control) -> ::Result<()> {
+            self.single = Some(ctrl.set_timeout(Duration::from_millis(20))?);
+            let tid = ctrl.set_timeout(Duration::from_millis(10))?;
+            ctrl.cancel_timer(tid)?;
+            assert_matches!(ctrl.cancel_timer(tid), Err(::Error::Id(IdError::Missing)));
+            self.interval = Some(ctrl.set_interval(Duration::from_millis(5))?);
+            Ok(())
+        }
+
+        fn detach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn process(&mut self, _ctrl: &mut Control, _readiness: mio::Ready, _eid: Eid) -> ::Result<()> {
+            panic!("timers must not be delivered to process");
+        }
+
+        fn timeout(&mut self, ctrl: &mut Control, tid: TimerId) -> ::Result<()> {
+            if Some(tid) == self.interval {
+                self.count += 1;
+                if self.count == 3 {
+                    ctrl.cancel_timer(self.interval.take().unwrap())?;
+                }
+                let _ = self.log.send("interval");
+            } else {
+                assert_eq!(Some(tid), self.single);
+                let _ = self.log.send("single");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn timers() {
+        loop_wrap(|el, tx| {
+            let (ltx, lrx) = channel();
+            let mut prx = PollReceiver::new(&lrx).unwrap();
+
+            let id = el.lock().unwrap().ids().alloc();
+            let tp = TimerProxy { log: ltx, single: None, interval: None, count: 0 };
+            tx.send(Rx::Attach(id, Box::new(tp))).unwrap();
+
+            let mut log = (0..4).map(|_| prx.recv(Some(Duration::from_secs(1))).unwrap()).collect::<Vec<_>>();
+            assert_matches!(prx.recv(Some(Duration::from_millis(50))), Err(RecvError::Empty));
+            log.sort();
+            assert_eq!(log, vec!["interval", "interval", "interval", "single"]);
+        });
+    }
+
+    struct ManyProxy {
+        regs: Vec<(mio::Registration, mio::SetReadiness)>,
+        log: Sender<Eid>,
+    }
+
+    impl Proxy for ManyProxy {
+        fn attach(&mut self, ctrl: &Control) -> ::Result<()> {
+            for (eid, (reg, _)) in self.regs.iter().enumerate() {
+                ctrl.register(reg, eid << 16, mio::Ready::readable(), mio::PollOpt::edge())?;
+            }
+            Ok(())
+        }
+
+        fn detach(&mut self, ctrl: &Control) -> ::Result<()> {
+            for (eid, (reg, _)) in self.regs.iter().enumerate() {
+                ctrl.deregister_eid(reg, eid << 16)?;
+            }
+            Ok(())
+        }
+
+        fn process(&mut self, _ctrl: &mut Control, _readiness: mio::Ready, eid: Eid) -> ::Result<()> {
+            let _ = self.log.send(eid);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn many_eids() {
+        let mut el = EventLoop::new().unwrap();
+        let (ltx, lrx) = channel();
+
+        let regs = (0..1000).map(|_| mio::Registration::new2()).collect::<Vec<_>>();
+        let srs = regs.iter().map(|(_, sr)| sr.clone()).collect::<Vec<_>>();
+        let id = el.attach(Box::new(ManyProxy { regs, log: ltx })).unwrap();
+        assert_eq!(el.tokens.borrow().len(), 1000);
+
+        srs[999].set_readiness(mio::Ready::readable()).unwrap();
+        el.run_once(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(lrx.try_recv().unwrap(), 999 << 16);
+
+        el.detach(id).unwrap();
+        assert_eq!(el.tokens.borrow().len(), 0);
+    }
+
+    #[test]
+    fn register_tokens() {
+        let el = EventLoop::new().unwrap();
+        let ctrl = el.control(1);
+        let (_tx, rx) = channel::<i32>();
+
+        ctrl.register(&rx, 1, mio::Ready::readable(), mio::PollOpt::edge()).unwrap();
+        assert_eq!(el.tokens.borrow().len(), 1);
+
+        // the failed registration doesn't leave the token
+        assert_matches!(ctrl.register(&rx, 2, mio::Ready::readable(), mio::PollOpt::edge()), Err(::Error::Io(_)));
+        assert_eq!(el.tokens.borrow().len(), 1);
+        // the token of the existing registration is kept
+        assert_matches!(ctrl.register(&rx, 1, mio::Ready::readable(), mio::PollOpt::edge()), Err(::Error::Io(_)));
+        assert_eq!(el.tokens.borrow().len(), 1);
+
+        ctrl.deregister_eid(&rx, 1).unwrap();
+        assert_eq!(el.tokens.borrow().len(), 0);
+
+        // the token is kept until the handle is actually deregistered
+        ctrl.register(&rx, 1, mio::Ready::readable(), mio::PollOpt::edge()).unwrap();
+        let (_tx2, rx2) = channel::<i32>();
+        assert_matches!(ctrl.deregister_eid(&rx2, 1), Err(::Error::Io(_)));
+        assert_eq!(el.tokens.borrow().len(), 1);
+        // the plain deregistration leaves the token to the proxy removal
+        ctrl.deregister(&rx).unwrap();
+        assert_eq!(el.tokens.borrow().len(), 1);
+        el.release(1);
+        assert_eq!(el.tokens.borrow().len(), 0);
+
+        for eid in 0..100 {
+            ctrl.register(&rx, eid, mio::Ready::readable(), mio::PollOpt::edge()).unwrap();
+            ctrl.deregister_eid(&rx, eid).unwrap();
+        }
+        assert_eq!(el.tokens.borrow().len(), 0);
+    }
+
+    struct LeakProxy {
+        reg: mio::Registration,
+        log: Sender<Id>,
+    }
+
+    impl Proxy for LeakProxy {
+        fn attach(&mut self, ctrl: &Control) -> ::Result<()> {
+            ctrl.register(&self.reg, 0, mio::Ready::readable(), mio::PollOpt::edge())
+        }
+
+        fn detach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            // the registration is left in the poll
+            Ok(())
+        }
+
+        fn process(&mut self, ctrl: &mut Control, _readiness: mio::Ready, _eid: Eid) -> ::Result<()> {
+            let _ = self.log.send(ctrl.id);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stale_token() {
+        let mut el = EventLoop::new().unwrap();
+        let (ltx, lrx) = channel();
+
+        let (reg, osr) = mio::Registration::new2();
+        let id = el.attach(Box::new(LeakProxy { reg, log: ltx.clone() })).unwrap();
+        let old = el.detach(id).unwrap();
+
+        let (reg, nsr) = mio::Registration::new2();
+        let nid = el.attach(Box::new(LeakProxy { reg, log: ltx })).unwrap();
+        assert!(nid != id);
+
+        osr.set_readiness(mio::Ready::readable()).unwrap();
+        el.run_once(Some(Duration::from_millis(10))).unwrap();
+        assert_matches!(lrx.try_recv(), Err(TryRecvError::Empty));
+
+        nsr.set_readiness(mio::Ready::readable()).unwrap();
+        el.run_once(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(lrx.try_recv().unwrap(), nid);
+
+        drop(old);
+    }
+}
@@ -0,0 +1,683 @@
+//! HiSLIP (IVI-6.1) client proxy.
+//!
+//! The proxy opens the synchronous and the asynchronous channels to the same server port
+//! and manages them as two endpoints of the single proxy.
+//! Data written through the handle is sent as complete messages over the synchronous channel
+//! and each complete response is reported with the message id of the request it answers.
+
+use std::io::{self};
+use std::convert::{TryFrom};
+use std::net::{SocketAddr};
+use std::collections::{VecDeque};
+
+use mio;
+use mio::net::{TcpStream};
+
+use ::channel::{Sender};
+use ::codec::{Codec};
+use ::proxy::{Proxy, Control, Eid};
+use ::proxy_handle::{self, Handle, UserProxy, UserHandle, TxExt, RxExt};
+use ::proxy_handle::{Tx as BaseTx, Rx as BaseRx};
+use ::stream_proxy::{Framed};
+
+
+/// Default HiSLIP port.
+pub const PORT: u16 = 4880;
+/// Maximum payload size of the messages accepted from the server.
+///
+/// The size is announced to the server with `AsyncMaximumMessageSize`
+/// and longer messages are rejected.
+pub const MAX_MESSAGE_SIZE: u64 = 1 << 20;
+
+const PROTOCOL_VERSION: u16 = 0x0100;
+const VENDOR_ID: [u8; 2] = *b"MD";
+const FIRST_MESSAGE_ID: u32 = 0xffff_ff00;
+const HEADER_SIZE: usize = 16;
+
+const SYNC: Eid = 1;
+const ASYNC: Eid = 2;
+
+const INITIALIZE: u8 = 0;
+const INITIALIZE_RESPONSE: u8 = 1;
+const FATAL_ERROR: u8 = 2;
+const ERROR: u8 = 3;
+const DATA: u8 = 6;
+const DATA_END: u8 = 7;
+const DEVICE_CLEAR_COMPLETE: u8 = 8;
+const DEVICE_CLEAR_ACKNOWLEDGE: u8 = 9;
+const INTERRUPTED: u8 = 13;
+const ASYNC_INTERRUPTED: u8 = 14;
+const ASYNC_MAXIMUM_MESSAGE_SIZE: u8 = 15;
+const ASYNC_MAXIMUM_MESSAGE_SIZE_RESPONSE: u8 = 16;
+const ASYNC_INITIALIZE: u8 = 17;
+const ASYNC_INITIALIZE_RESPONSE: u8 = 18;
+const ASYNC_DEVICE_CLEAR: u8 = 19;
+const ASYNC_SERVICE_REQUEST: u8 = 20;
+const ASYNC_STATUS_QUERY: u8 = 21;
+const ASYNC_STATUS_RESPONSE: u8 = 22;
+const ASYNC_DEVICE_CLEAR_ACKNOWLEDGE: u8 = 23;
+
+const OVERLAPPED: u8 = 0x01;
+const RMT_DELIVERED: u8 = 0x01;
+
+
+#[derive(Debug, TxExt)]
+pub enum Tx {
+    #[mdrv(base)]
+    Base(BaseTx),
+    /// Send the data to the device as a single message.
+    Write(Vec<u8>),
+    /// Query the status byte over the asynchronous channel.
+    ReadStb,
+    /// Perform the device clear handshake.
+    Clear,
+}
+
+#[derive(Debug, RxExt)]
+pub enum Rx {
+    #[mdrv(base)]
+    Base(BaseRx),
+    /// Both channels are initialized.
+    Connected { session_id: u16, overlapped: bool },
+    /// The data is sent with the message id.
+    Sent(u32),
+    /// Complete response to the message with the id.
+    Data { id: u32, data: Vec<u8> },
+    Stb(u8),
+    /// Service request with the status byte.
+    Srq(u8),
+    /// The device clear is complete and the mode negotiated.
+    Cleared { overlapped: bool },
+    /// Non-fatal error reported by the server.
+    ServerError(u8, String),
+}
+
+
+/// HiSLIP message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub kind: u8,
+    pub control: u8,
+    pub param: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(kind: u8, control: u8, param: u32, payload: Vec<u8>) -> Self {
+        Self { kind, control, param, payload }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        buf.extend_from_slice(b"HS");
+        buf.push(self.kind);
+        buf.push(self.control);
+        buf.extend_from_slice(&self.param.to_be_bytes());
+        buf.extend_from_slice(&(self.payload.len() as u64).to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    /// Decodes the message from the beginning of the buffer
+    /// and returns it with its encoded size if the buffer contains the whole message.
+    ///
+    /// The message with the payload longer than `max_size` is rejected with `InvalidData`.
+    pub fn decode(buf: &[u8], max_size: u64) -> io::Result<Option<(Self, usize)>> {
+        if buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        if &buf[..2] != b"HS" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad HiSLIP message prologue"));
+        }
+        let param = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let mut len = [0; 8];
+        len.copy_from_slice(&buf[8..16]);
+        let len = u64::from_be_bytes(len);
+        if len > max_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "HiSLIP message is too long"));
+        }
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "HiSLIP message is too long"))?;
+        if buf.len() - HEADER_SIZE < len {
+            return Ok(None);
+        }
+        let payload = buf[HEADER_SIZE..(HEADER_SIZE + len)].to_vec();
+        Ok(Some((Self::new(buf[2], buf[3], param, payload), HEADER_SIZE + len)))
+    }
+}
+
+
+/// Codec of HiSLIP messages with the limited payload size.
+pub struct HislipCodec {
+    max_size: u64,
+}
+
+impl HislipCodec {
+    pub fn new(max_size: u64) -> Self {
+        Self { max_size }
+    }
+}
+
+impl Codec for HislipCodec {
+    type In = Message;
+    type Out = Message;
+
+    fn decode(&mut self, buf: &[u8]) -> io::Result<Option<(Message, usize)>> {
+        Message::decode(buf, self.max_size)
+    }
+
+    fn encode(&mut self, msg: Message, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(&msg.encode());
+        Ok(())
+    }
+}
+
+
+/// Non-blocking TCP stream exchanging HiSLIP messages.
+struct Channel {
+    stream: TcpStream,
+    framed: Framed<HislipCodec>,
+}
+
+impl Channel {
+    fn connect(ctrl: &Control, addr: &SocketAddr, eid: Eid) -> ::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        ctrl.register(&stream, eid, mio::Ready::readable() | mio::Ready::writable(), mio::PollOpt::edge())?;
+        Ok(Self { stream, framed: Framed::new(HislipCodec::new(MAX_MESSAGE_SIZE)) })
+    }
+
+    fn send(&mut self, msg: Message) -> io::Result<()> {
+        self.framed.encode(msg)?;
+        self.framed.flush(&mut self.stream)
+    }
+
+    fn ready(&mut self) -> io::Result<()> {
+        if let Some(err) = self.stream.take_error()? {
+            return Err(err);
+        }
+        self.framed.flush(&mut self.stream)
+    }
+
+    fn recv(&mut self) -> ::Result<(Vec<Message>, bool)> {
+        let mut msgs = Vec::new();
+        let eof = self.framed.fill(&mut self.stream, |msg| {
+            msgs.push(msg);
+            Ok(true)
+        })?;
+        Ok((msgs, eof))
+    }
+}
+
+
+pub struct HislipProxy {
+    addr: SocketAddr,
+    sub_address: String,
+    tx: Sender<Rx>,
+    sync: Option<Channel>,
+    async_: Option<Channel>,
+    session_id: Option<u16>,
+    connected: bool,
+    overlapped: bool,
+    clearing: bool,
+    message_id: u32,
+    /// Maximum payload size of the messages accepted by the server.
+    max_size: u64,
+    rmt_delivered: bool,
+    partial: Vec<u8>,
+    partial_id: u32,
+    queue: VecDeque<Tx>,
+}
+
+fn protocol(msg: &str) -> ::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+impl HislipProxy {
+    fn new(addr: SocketAddr, sub_address: String, tx: Sender<Rx>) -> Self {
+        Self {
+            addr, sub_address, tx,
+            sync: None,
+            async_: None,
+            session_id: None,
+            connected: false,
+            overlapped: false,
+            clearing: false,
+            message_id: FIRST_MESSAGE_ID,
+            max_size: u64::MAX,
+            rmt_delivered: false,
+            partial: Vec::new(),
+            partial_id: 0,
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn send(&self, msg: Rx) -> ::Result<()> {
+        self.tx.send(msg).map_err(|e| ::Error::Channel(e.into()))
+    }
+
+    fn send_sync(&mut self, msg: Message) -> ::Result<()> {
+        self.sync.as_mut().ok_or_else(|| protocol("sync channel is not connected"))?.send(msg)?;
+        Ok(())
+    }
+
+    fn send_async(&mut self, msg: Message) -> ::Result<()> {
+        self.async_.as_mut().ok_or_else(|| protocol("async channel is not connected"))?.send(msg)?;
+        Ok(())
+    }
+
+    fn rmt(&mut self) -> u8 {
+        if self.rmt_delivered {
+            self.rmt_delivered = false;
+            RMT_DELIVERED
+        } else {
+            0
+        }
+    }
+
+    /// Executes queued operations until the device clear is started.
+    fn next(&mut self) -> ::Result<()> {
+        while self.connected && !self.clearing {
+            match self.queue.pop_front() {
+                Some(Tx::Write(data)) => {
+                    // The server interrupts the pending response in synchronized mode
+                    if !self.overlapped {
+                        self.partial.clear();
+                    }
+                    let id = self.message_id;
+                    let control = self.rmt();
+                    // Split the data into the messages the server is able to accept
+                    let size = usize::try_from(self.max_size).unwrap_or(usize::MAX).max(1);
+                    let mut chunks = data.chunks(size).peekable();
+                    while let Some(chunk) = chunks.next() {
+                        let kind = if chunks.peek().is_some() { DATA } else { DATA_END };
+                        self.send_sync(Message::new(kind, control, id, chunk.to_vec()))?;
+                    }
+                    if data.is_empty() {
+                        self.send_sync(Message::new(DATA_END, control, id, data))?;
+                    }
+                    self.message_id = id.wrapping_add(2);
+                    self.send(Rx::Sent(id))?;
+                },
+                Some(Tx::ReadStb) => {
+                    let control = self.rmt();
+                    let id = self.message_id.wrapping_sub(2);
+                    self.send_async(Message::new(ASYNC_STATUS_QUERY, control, id, Vec::new()))?;
+                },
+                Some(Tx::Clear) => {
+                    self.clearing = true;
+                    self.send_async(Message::new(ASYNC_DEVICE_CLEAR, 0, 0, Vec::new()))?;
+                },
+                Some(Tx::Base(_)) => (),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards the partial response to the message preceding the interrupting one.
+    fn interrupted(&mut self, id: u32) {
+        if self.partial_id != id {
+            self.partial.clear();
+        }
+    }
+
+    fn process_sync(&mut self, ctrl: &Control, msg: Message) -> ::Result<()> {
+        match msg.kind {
+            INITIALIZE_RESPONSE => {
+                self.overlapped = msg.control & OVERLAPPED != 0;
+                let session_id = msg.param as u16;
+                self.session_id = Some(session_id);
+                let mut channel = Channel::connect(ctrl, &self.addr, ASYNC)?;
+                channel.send(Message::new(ASYNC_INITIALIZE, 0, session_id as u32, Vec::new()))?;
+                self.async_ = Some(channel);
+            },
+            DATA => {
+                self.partial_id = msg.param;
+                self.partial.extend(msg.payload);
+            },
+            DATA_END => {
+                let mut data = self.partial.split_off(0);
+                data.extend(msg.payload);
+                self.rmt_delivered = true;
+                self.send(Rx::Data { id: msg.param, data })?;
+            },
+            INTERRUPTED => self.interrupted(msg.param),
+            DEVICE_CLEAR_ACKNOWLEDGE => {
+                self.overlapped = msg.control & OVERLAPPED != 0;
+                self.clearing = false;
+                self.message_id = FIRST_MESSAGE_ID;
+                self.rmt_delivered = false;
+                self.partial.clear();
+                self.send(Rx::Cleared { overlapped: self.overlapped })?;
+                self.next()?;
+            },
+            _ => self.process_common(msg)?,
+        }
+        Ok(())
+    }
+
+    fn process_async(&mut self, msg: Message) -> ::Result<()> {
+        match msg.kind {
+            ASYNC_INITIALIZE_RESPONSE => {
+                let size = MAX_MESSAGE_SIZE.to_be_bytes().to_vec();
+                self.send_async(Message::new(ASYNC_MAXIMUM_MESSAGE_SIZE, 0, 0, size))?;
+            },
+            ASYNC_MAXIMUM_MESSAGE_SIZE_RESPONSE => {
+                if msg.payload.len() != 8 {
+                    return Err(protocol("bad HiSLIP maximum message size"));
+                }
+                let mut size = [0; 8];
+                size.copy_from_slice(&msg.payload);
+                self.max_size = u64::from_be_bytes(size);
+                self.connected = true;
+                let session_id = self.session_id.unwrap_or(0);
+                self.send(Rx::Connected { session_id, overlapped: self.overlapped })?;
+                self.next()?;
+            },
+            ASYNC_SERVICE_REQUEST => self.send(Rx::Srq(msg.control))?,
+            ASYNC_STATUS_RESPONSE => self.send(Rx::Stb(msg.control))?,
+            ASYNC_INTERRUPTED => self.interrupted(msg.param),
+            ASYNC_DEVICE_CLEAR_ACKNOWLEDGE => {
+                // Accept the feature preference of the server
+                self.partial.clear();
+                self.send_sync(Message::new(DEVICE_CLEAR_COMPLETE, msg.control, 0, Vec::new()))?;
+            },
+            _ => self.process_common(msg)?,
+        }
+        Ok(())
+    }
+
+    fn process_common(&mut self, msg: Message) -> ::Result<()> {
+        let text = String::from_utf8_lossy(&msg.payload).into_owned();
+        match msg.kind {
+            ERROR => self.send(Rx::ServerError(msg.control, text)),
+            FATAL_ERROR => Err(io::Error::other(format!("HiSLIP fatal error {}: {}", msg.control, text)).into()),
+            other => Err(protocol(&format!("unexpected HiSLIP message type {}", other))),
+        }
+    }
+}
+
+impl Proxy for HislipProxy {
+    fn attach(&mut self, ctrl: &Control) -> ::Result<()> {
+        let mut channel = Channel::connect(ctrl, &self.addr, SYNC)?;
+        let param = ((PROTOCOL_VERSION as u32) << 16) | ((VENDOR_ID[0] as u32) << 8) | VENDOR_ID[1] as u32;
+        channel.send(Message::new(INITIALIZE, 0, param, self.sub_address.as_bytes().to_vec()))?;
+        self.sync = Some(channel);
+        Ok(())
+    }
+
+    fn detach(&mut self, ctrl: &Control) -> ::Result<()> {
+        // The session is closed, so it is initialized again on the next attachment
+        self.session_id = None;
+        self.connected = false;
+        self.clearing = false;
+        self.message_id = FIRST_MESSAGE_ID;
+        self.max_size = u64::MAX;
+        self.rmt_delivered = false;
+        self.partial.clear();
+        if let Some(channel) = self.sync.take() {
+            ctrl.deregister_eid(&channel.stream, SYNC)?;
+        }
+        if let Some(channel) = self.async_.take() {
+            ctrl.deregister_eid(&channel.stream, ASYNC)?;
+        }
+        Ok(())
+    }
+
+    fn process(&mut self, ctrl: &mut Control, readiness: mio::Ready, eid: Eid) -> ::Result<()> {
+        let (msgs, eof) = {
+            let channel = match eid {
+                SYNC => self.sync.as_mut(),
+                ASYNC => self.async_.as_mut(),
+                _ => None,
+            };
+            let channel = match channel {
+                Some(channel) => channel,
+                None => return Ok(()),
+            };
+            channel.ready()?;
+            if !readiness.is_readable() {
+                return Ok(());
+            }
+            channel.recv()?
+        };
+        for msg in msgs {
+            match eid {
+                SYNC => self.process_sync(ctrl, msg)?,
+                _ => self.process_async(msg)?,
+            }
+        }
+        if eof {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed by instrument").into());
+        }
+        Ok(())
+    }
+}
+
+impl UserProxy<Tx, Rx> for HislipProxy {
+    fn process_channel(&mut self, _ctrl: &mut Control, msg: Tx) -> ::Result<()> {
+        if let Tx::Base(_) = msg {
+            return Ok(());
+        }
+        self.queue.push_back(msg);
+        self.next()
+    }
+}
+
+pub struct HislipHandle {
+    pub msgs: VecDeque<Rx>,
+}
+
+impl HislipHandle {
+    fn new() -> Self {
+        Self { msgs: VecDeque::new() }
+    }
+}
+
+impl UserHandle<Tx, Rx> for HislipHandle {
+    fn process_channel(&mut self, msg: Rx) -> ::Result<()> {
+        self.msgs.push_back(msg);
+        Ok(())
+    }
+}
+
+/// Creates the proxy for the `sub_address` (e.g. `"hislip0"`) of the server at `addr` and its handle.
+///
+/// The connection is made when the proxy is attached to the event loop.
+pub fn create(addr: SocketAddr, sub_address: &str) -> ::Result<proxy_handle::Pair<HislipProxy, HislipHandle, Tx, Rx>> {
+    let sub_address = sub_address.to_string();
+    proxy_handle::create_with(|tx| HislipProxy::new(addr, sub_address, tx), HislipHandle::new())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+    use std::time::{Duration};
+    use std::net::{TcpListener};
+    use std::io::{Read, Write};
+
+    use ::driver::{Driver};
+    use ::proxy_handle::{DetachReason};
+
+    fn read_msg<R: Read>(reader: &mut R) -> Message {
+        let mut buf = vec![0; HEADER_SIZE];
+        reader.read_exact(&mut buf).unwrap();
+        let mut len = [0; 8];
+        len.copy_from_slice(&buf[8..16]);
+        let start = buf.len();
+        buf.resize(start + u64::from_be_bytes(len) as usize, 0);
+        reader.read_exact(&mut buf[start..]).unwrap();
+        Message::decode(&buf, u64::MAX).unwrap().unwrap().0
+    }
+
+    fn write_msg<W: Write>(writer: &mut W, kind: u8, control: u8, param: u32, payload: &[u8]) {
+        writer.write_all(&Message::new(kind, control, param, payload.to_vec()).encode()).unwrap();
+    }
+
+    /// Accepts both channels of the session.
+    fn accept(listener: &TcpListener, overlapped: bool, max_size: u64) -> (::std::net::TcpStream, ::std::net::TcpStream) {
+        let (mut sync, _) = listener.accept().unwrap();
+        let msg = read_msg(&mut sync);
+        assert_eq!((msg.kind, msg.param >> 16, msg.payload.as_slice()), (INITIALIZE, PROTOCOL_VERSION as u32, &b"hislip0"[..]));
+        write_msg(&mut sync, INITIALIZE_RESPONSE, overlapped as u8, (PROTOCOL_VERSION as u32) << 16 | 7, &[]);
+
+        let (mut async_, _) = listener.accept().unwrap();
+        let msg = read_msg(&mut async_);
+        assert_eq!((msg.kind, msg.param), (ASYNC_INITIALIZE, 7));
+        write_msg(&mut async_, ASYNC_INITIALIZE_RESPONSE, 0, 0x4d4b, &[]);
+        let msg = read_msg(&mut async_);
+        assert_eq!((msg.kind, msg.payload), (ASYNC_MAXIMUM_MESSAGE_SIZE, MAX_MESSAGE_SIZE.to_be_bytes().to_vec()));
+        write_msg(&mut async_, ASYNC_MAXIMUM_MESSAGE_SIZE_RESPONSE, 0, 0, &max_size.to_be_bytes());
+        (sync, async_)
+    }
+
+    fn wait_msg(h: &mut Handle<HislipHandle, Tx, Rx>) -> Rx {
+        h.wait_until(|u| !u.msgs.is_empty(), Some(Duration::from_secs(10))).unwrap();
+        h.user.msgs.pop_front().unwrap()
+    }
+
+    #[test]
+    fn codec() {
+        let msg = Message::new(DATA_END, RMT_DELIVERED, FIRST_MESSAGE_ID, b"*IDN?\n".to_vec());
+        let mut buf = msg.encode();
+        assert_eq!(buf.len(), HEADER_SIZE + 6);
+        assert_eq!(Message::decode(&buf[..10], 6).unwrap(), None);
+        buf.extend_from_slice(b"HS");
+        assert_eq!(Message::decode(&buf, 6).unwrap(), Some((msg, HEADER_SIZE + 6)));
+        assert_matches!(Message::decode(&buf, 5), Err(ref e) if e.kind() == io::ErrorKind::InvalidData);
+        assert_matches!(Message::decode(b"XX0123456789abcdef", 6), Err(_));
+    }
+
+    #[test]
+    fn codec_huge_length() {
+        let mut buf = Message::new(DATA_END, 0, 0, Vec::new()).encode();
+        buf[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_matches!(Message::decode(&buf, MAX_MESSAGE_SIZE), Err(ref e) if e.kind() == io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn max_message_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            let (mut sync, _async) = accept(&listener, false, 4);
+
+            let msg = read_msg(&mut sync);
+            assert_eq!((msg.kind, msg.param, msg.payload.as_slice()), (DATA, FIRST_MESSAGE_ID, &b"*IDN"[..]));
+            let msg = read_msg(&mut sync);
+            assert_eq!((msg.kind, msg.param, msg.payload.as_slice()), (DATA_END, FIRST_MESSAGE_ID, &b"?\n"[..]));
+            let mut buf = Message::new(DATA_END, 0, FIRST_MESSAGE_ID, Vec::new()).encode();
+            buf[8..16].copy_from_slice(&(MAX_MESSAGE_SIZE + 1).to_be_bytes());
+            sync.write_all(&buf).unwrap();
+            // keep the connection until the client closes it
+            let _ = sync.read(&mut [0; 1]);
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr, "hislip0").unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        h.tx.send(Tx::Write(b"*IDN?\n".to_vec())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Connected { .. });
+        assert_matches!(wait_msg(&mut h), Rx::Sent(FIRST_MESSAGE_ID));
+        assert_matches!(
+            wait_msg(&mut h),
+            Rx::Base(BaseRx::Detached { reason: DetachReason::Error(::Error::Io(ref e)) }) if e.kind() == io::ErrorKind::InvalidData
+        );
+
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            let (mut sync, mut async_) = accept(&listener, true, u64::MAX);
+
+            let msg = read_msg(&mut sync);
+            assert_eq!((msg.kind, msg.param, msg.payload.as_slice()), (DATA_END, FIRST_MESSAGE_ID, &b"*IDN?\n"[..]));
+            write_msg(&mut sync, DATA, 0, FIRST_MESSAGE_ID, b"FAKE,");
+            write_msg(&mut sync, DATA_END, 0, FIRST_MESSAGE_ID, b"HISLIP\n");
+
+            let msg = read_msg(&mut async_);
+            assert_eq!((msg.kind, msg.control), (ASYNC_STATUS_QUERY, RMT_DELIVERED));
+            write_msg(&mut async_, ASYNC_SERVICE_REQUEST, 0x40, 0, &[]);
+            write_msg(&mut async_, ASYNC_STATUS_RESPONSE, 0x10, 0, &[]);
+
+            assert_eq!(read_msg(&mut async_).kind, ASYNC_DEVICE_CLEAR);
+            write_msg(&mut async_, ASYNC_DEVICE_CLEAR_ACKNOWLEDGE, 0, 0, &[]);
+            let msg = read_msg(&mut sync);
+            assert_eq!((msg.kind, msg.control), (DEVICE_CLEAR_COMPLETE, 0));
+            write_msg(&mut sync, DEVICE_CLEAR_ACKNOWLEDGE, 0, 0, &[]);
+
+            let msg = read_msg(&mut sync);
+            assert_eq!((msg.kind, msg.param), (DATA_END, FIRST_MESSAGE_ID));
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr, "hislip0").unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Connected { session_id: 7, overlapped: true });
+
+        h.tx.send(Tx::Write(b"*IDN?\n".to_vec())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Sent(FIRST_MESSAGE_ID));
+        assert_matches!(wait_msg(&mut h), Rx::Data { id: FIRST_MESSAGE_ID, ref data } if data == b"FAKE,HISLIP\n");
+
+        h.tx.send(Tx::ReadStb).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Srq(0x40));
+        assert_matches!(wait_msg(&mut h), Rx::Stb(0x10));
+
+        h.tx.send(Tx::Clear).unwrap();
+        h.tx.send(Tx::Write(b"*RST\n".to_vec())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Cleared { overlapped: false });
+        assert_matches!(wait_msg(&mut h), Rx::Sent(FIRST_MESSAGE_ID));
+
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn interrupted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            let (mut sync, mut async_) = accept(&listener, false, u64::MAX);
+
+            read_msg(&mut sync);
+            write_msg(&mut sync, DATA, 0, FIRST_MESSAGE_ID, b"PARTIAL");
+            let msg = read_msg(&mut sync);
+            assert_eq!(msg.param, FIRST_MESSAGE_ID + 2);
+            write_msg(&mut async_, ASYNC_INTERRUPTED, 0, FIRST_MESSAGE_ID + 2, &[]);
+            write_msg(&mut sync, INTERRUPTED, 0, FIRST_MESSAGE_ID + 2, &[]);
+            write_msg(&mut sync, ERROR, 3, 0, b"Unrecognized message type");
+            write_msg(&mut sync, DATA_END, 0, FIRST_MESSAGE_ID + 2, b"COMPLETE\n");
+            write_msg(&mut sync, FATAL_ERROR, 0, 0, b"Unidentified error");
+            // keep the connection until the client closes it
+            let _ = sync.read(&mut [0; 1]);
+            drop(async_);
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr, "hislip0").unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        h.tx.send(Tx::Write(b"*IDN?\n".to_vec())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Connected { session_id: 7, overlapped: false });
+        assert_matches!(wait_msg(&mut h), Rx::Sent(FIRST_MESSAGE_ID));
+
+        h.tx.send(Tx::Write(b"MEAS?\n".to_vec())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Sent(_));
+        assert_matches!(wait_msg(&mut h), Rx::ServerError(3, _));
+        assert_matches!(wait_msg(&mut h), Rx::Data { ref data, .. } if data == b"COMPLETE\n");
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Detached { reason: DetachReason::Error(::Error::Io(_)) }));
+
+        thr.join().unwrap();
+    }
+}
@@ -4,6 +4,7 @@
 //! 
 //! The core of the [`Mdrv`] funtionality is the [`Driver`] structure.
 //! In maintains an event loop which manages entities called proxies.
+//! The [`EventLoop`] could also be used without the driver, if it is needed to run it in the caller's thread.
 //! 
 //! [`Proxy`] instances are passed to the driver to be inserted in event loop.
 //! They can maintain different connections and reads and writes data over them.
@@ -17,31 +18,39 @@
 //! Also a command set should be defined for communication between proxy and handle.
 //! These commands have to be able to convert from and into the basic [`Tx`] and [`Rx`] commands
 //! by implementing [`From`] and [`Into`] traits.
+//! The conversions are usually derived with `#[derive(TxExt)]` and `#[derive(RxExt)]`
+//! from the variant marked with `#[mdrv(base)]`.
+//! 
+//! Many handles could be waited for at once by collecting them into a [`HandleSet`].
+//! Messages of the proxy could be also received by other consumers subscribed with [`Handle::subscribe()`].
 //! 
 //! The example dummy implementation of user structures could be found in [`dummy`] module.
+//! New stream protocols could implement only the message framing as a [`Codec`] and use the [`StreamProxy`].
+//! The [`reconnect`] module provides the stream proxy that survives connection loss.
+//! The [`scpi`], [`vxi11`] and [`hislip`] modules contain ready-made proxies for instrument connections.
+//!
+//! With the `futures` feature enabled the [`Handle`] is also a `futures::Stream` of received messages
+//! and a `futures::Sink` of messages to send, so it could be awaited from any executor.
 //! 
 //! # Simple example
 //!
 //! ```rust
-//! use mdrv::{channel, driver, dummy};
+//! use mdrv::{driver, dummy};
 //! 
 //! // create driver instance
 //! let mut driver = driver::Driver::new().unwrap();
 //! // create dummy proxy and handle pair
 //! let (proxy, mut handle) = dummy::create().unwrap();
-//!
-//! // We need a simple poll to wait for messages on handle
-//! // A regular mio::Poll also can be used for that
-//! let mut poll = channel::SinglePoll::new(&handle.rx).unwrap();
 //! 
 //! driver.attach(Box::new(proxy)).unwrap();
 //!
-//! // wait for one message from proxy to arrive
-//! dummy::wait_msgs(&mut handle, &mut poll, 1).unwrap();
+//! // wait for a message from proxy to arrive
+//! // the handle polls its receiver internally, a regular mio::Poll also can be used for that
+//! handle.wait(None).unwrap();
 //! 
 //! // read message received
 //! match handle.user.msgs.pop_front().unwrap() {
-//!     dummy::Rx::Attached => println!("attached to the driver"),
+//!     dummy::Rx::Attached { .. } => println!("attached to the driver"),
 //!     other => panic!("{:?}", other),
 //! }
 //!
@@ -49,11 +58,11 @@
 //! handle.close().unwrap(); // this also called on handle drop
 //! 
 //! // wait for proxy to be closed
-//! dummy::wait_close(&mut handle, &mut poll).unwrap();
+//! handle.wait_close(None).unwrap();
 //! 
 //! // read messages again
 //! match handle.user.msgs.pop_front().unwrap() {
-//!     dummy::Rx::Detached => println!("detached from the driver"),
+//!     dummy::Rx::Detached { .. } => println!("detached from the driver"),
 //!     other => panic!("{:?}", other),
 //! }
 //! match handle.user.msgs.pop_front().unwrap() {
@@ -66,11 +75,16 @@
 //! [`Mio`]: https://github.com/carllerche/mio
 //!
 //! [`Driver`]: driver/struct.Driver.html
+//! [`EventLoop`]: event_loop/struct.EventLoop.html
 //! [`Proxy`]: proxy/trait.Proxy.html
 //! [`Handle`]: proxy_handle/struct.Handle.html
 //! [`ProxyWrapper`]: proxy_handle/struct.ProxyWrapper.html
+//! [`HandleSet`]: proxy_handle/struct.HandleSet.html
+//! [`Handle::subscribe()`]: proxy_handle/struct.Handle.html#method.subscribe
 //! [`create()`]: proxy_handle/fn.create.html
 //! 
+//! [`Codec`]: codec/trait.Codec.html
+//! [`StreamProxy`]: stream_proxy/struct.StreamProxy.html
 //! [`UserProxy`]: proxy_handle/trait.UserProxy.html
 //! [`UserHandle`]: proxy_handle/trait.UserHandle.html
 //! [`Tx`]: proxy_handle/enum.Tx.html
@@ -80,10 +94,22 @@
 //! [`Into`]: https://doc.rust-lang.org/nightly/core/convert/trait.Into.html
 //! 
 //! [`dummy`]: dummy/index.html
+//! [`reconnect`]: reconnect/index.html
+//! [`scpi`]: scpi/index.html
+//! [`vxi11`]: vxi11/index.html
+//! [`hislip`]: hislip/index.html
 //! 
 
 extern crate mio;
 extern crate mio_extras;
+#[cfg(target_os = "linux")]
+extern crate libc;
+#[cfg(feature = "futures")]
+extern crate futures;
+extern crate mdrv_derive;
+
+// Derived impls refer to the crate by name
+extern crate self as mdrv;
 
 pub mod error;
 pub mod result;
@@ -92,8 +118,18 @@ pub mod channel;
 pub mod proxy;
 pub mod proxy_handle;
 pub mod dummy;
+pub mod codec;
+pub mod stream_proxy;
+pub mod reconnect;
+pub mod block;
+pub mod scpi;
+mod rpc;
+pub mod vxi11;
+pub mod hislip;
 
-mod event_loop;
+mod timer;
+mod token;
+pub mod event_loop;
 pub mod driver;
 
 pub use error::{Error};
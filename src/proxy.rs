@@ -1,37 +1,23 @@
 use std::error::{Error as StdError};
 use std::fmt;
+use std::cell::{RefCell};
+use std::time::{Duration};
 
 use mio;
 
-use ::error::{IdError};
+use ::timer::{Timers};
+use ::token::{Tokens};
 
 
 pub type Id = usize;
 pub type Eid = usize;
-
-pub const EID_BITS: usize = 8;
-pub const EID_MASK: usize = (1<<EID_BITS) - 1;
-
-pub fn encode_ids(id: Id, port: Eid) -> Option<mio::Token> {
-    let base = id << EID_BITS;
-    if base >> EID_BITS != id {
-        return None;
-    }
-    if port & EID_MASK != port {
-        return None;
-    }
-    Some(mio::Token(base | port))
-}
-
-pub fn decode_ids(token: mio::Token) -> (Id, Eid) {
-    let id = token.0 >> EID_BITS;
-    let port = token.0 & EID_MASK;
-    (id, port)
-}
+pub type TimerId = usize;
 
 #[derive(Debug)]
 pub enum Error {
-    Closed
+    Closed,
+    Panicked,
+    NotRequest,
 }
 
 
@@ -39,12 +25,16 @@ impl StdError for Error {
     fn description(&self) -> &str {
         match self {
             Error::Closed => "Proxy detached",
+            Error::Panicked => "Proxy panicked",
+            Error::NotRequest => "Message is not a request",
         }
     }
 
     fn cause(&self) -> Option<&StdError> {
         match self {
             Error::Closed => None,
+            Error::Panicked => None,
+            Error::NotRequest => None,
         }
     }
 }
@@ -59,30 +49,90 @@ impl fmt::Display for Error {
 pub struct Control<'a> {
     pub(crate) id: Id,
     pub(crate) poll: &'a mio::Poll,
+    pub(crate) timers: &'a RefCell<Timers>,
+    pub(crate) tokens: &'a RefCell<Tokens>,
     pub(crate) closed: bool,
 }
 
 impl<'a> Control<'a> {
-    pub(crate) fn new(id: Id, poll: &'a mio::Poll) -> Self {
-        Self { id, poll, closed: false }
+    pub(crate) fn new(id: Id, poll: &'a mio::Poll, timers: &'a RefCell<Timers>, tokens: &'a RefCell<Tokens>) -> Self {
+        Self { id, poll, timers, tokens, closed: false }
     }
 
+    /// Registers the handle in the event loop, its events are passed to `Proxy::process` with specified `eid`.
+    ///
+    /// Any `eid` value is allowed, but the same `eid` shouldn't be used for different handles at once.
     pub fn register<E: mio::Evented>(&self, handle: &E, eid: Eid, interest: mio::Ready, opts: mio::PollOpt) -> ::Result<()> {
+        let present = self.tokens.borrow().contains(self.id, eid);
+        let token = self.tokens.borrow_mut().insert(self.id, eid)?;
         self.poll.register(
             handle, 
-            encode_ids(self.id, eid).ok_or(::Error::from(IdError::Bad))?, 
+            token, 
             interest, 
             opts,
-        ).map_err(|e| ::Error::from(e))
+        ).map_err(|e| {
+            if !present {
+                self.tokens.borrow_mut().remove_eid(self.id, eid);
+            }
+            ::Error::from(e)
+        })
     }
 
+    /// Deregisters the handle from the event loop.
+    ///
+    /// The token of the handle is kept until the proxy is detached,
+    /// so the events that are already pending are still passed to the proxy.
     pub fn deregister<E: mio::Evented>(&self, handle: &E) -> ::Result<()> {
         self.poll.deregister(handle).map_err(|e| ::Error::from(e))
     }
 
+    /// Deregisters the handle registered with `eid` and frees its token.
+    ///
+    /// Events of the handle that are already pending are discarded.
+    pub fn deregister_eid<E: mio::Evented>(&self, handle: &E, eid: Eid) -> ::Result<()> {
+        self.poll.deregister(handle).map_err(|e| ::Error::from(e))?;
+        self.tokens.borrow_mut().remove_eid(self.id, eid);
+        Ok(())
+    }
+
+    /// Schedules a single timeout.
+    ///
+    /// When the timeout expires the `Proxy::timeout` is called with the returned timer id.
+    /// Timers are cancelled automatically on proxy detachment.
+    pub fn set_timeout(&self, delay: Duration) -> ::Result<TimerId> {
+        Ok(self.timers.borrow_mut().set(self.id, delay, None))
+    }
+
+    /// Schedules a periodic timer which expires every `period` until cancelled.
+    pub fn set_interval(&self, period: Duration) -> ::Result<TimerId> {
+        Ok(self.timers.borrow_mut().set(self.id, period, Some(period)))
+    }
+
+    /// Cancels the timer. Fails if the timer has already expired or doesn't belong to the proxy.
+    pub fn cancel_timer(&self, tid: TimerId) -> ::Result<()> {
+        self.timers.borrow_mut().cancel(self.id, tid)
+    }
+
     pub fn close(&mut self) {
         self.closed = true;
     }
+
+    /// Id of the proxy in the event loop.
+    pub fn id(&self) -> Id {
+        self.id
+    }
+}
+
+/// What the event loop does with the proxy after it has returned an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Detach the proxy from the event loop and drop it.
+    #[default]
+    Detach,
+    /// Keep the proxy attached as if nothing happened.
+    Ignore,
+    /// Detach the proxy and then attach it again with the same id.
+    Restart,
 }
 
 pub trait Proxy {
@@ -90,32 +140,24 @@ pub trait Proxy {
     fn detach(&mut self, ctrl: &Control) -> ::Result<()>;
 
     fn process(&mut self, ctrl: &mut Control, readiness: mio::Ready, eid: Eid) -> ::Result<()>;
-}
-
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    use std::mem;
 
+    /// Called when the timer set by `Control::set_timeout` or `Control::set_interval` expires.
+    fn timeout(&mut self, _ctrl: &mut Control, _tid: TimerId) -> ::Result<()> {
+        Ok(())
+    }
 
-    #[test]
-    fn ids_encode() {
-        assert_eq!(
-            (456 << EID_BITS) | 123,
-            encode_ids(456, 123).unwrap().0
-        );
+    /// Receives an error that was returned by one of the proxy methods.
+    ///
+    /// The error doesn't leave the event loop, so this is the only place where it can be reported.
+    fn error(&mut self, _ctrl: &Control, _err: ::Error) {}
 
-        assert!(encode_ids(0, (1<<EID_BITS)+1).is_none());
-        assert!(encode_ids(1<<(8*mem::size_of::<usize>()-1), 0).is_none());
-    }
+    /// Receives the message of the panic occured in one of the proxy methods.
+    ///
+    /// The proxy is dropped by the event loop right after this call.
+    fn panicked(&mut self, _msg: String) {}
 
-    #[test]
-    fn ids_decode() {
-        assert_eq!(
-            decode_ids(mio::Token((456 << EID_BITS) | 123)),
-            (456, 123)
-        );
+    /// Tells the event loop how to handle errors of this proxy.
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::default()
     }
 }
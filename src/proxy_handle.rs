@@ -1,7 +1,18 @@
+use std::time::{Duration, Instant};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "futures")]
+use std::pin::{Pin};
+#[cfg(feature = "futures")]
+use std::task::{self, Context};
+
 use mio;
+#[cfg(feature = "futures")]
+use futures::{Stream, Sink};
 
-use ::channel::{self, channel, Sender, Receiver, SendError, TryRecvError};
-use ::proxy::{self, Proxy, Control, Eid};
+use ::error::{IdError};
+use ::channel::{self, channel, sync_channel, Sender, Receiver, SendError, TrySendError, TryRecvError, RecvError, SinglePoll, MultiPoll};
+use ::proxy::{self, Proxy, Control, Id, Eid, TimerId, ErrorPolicy};
 
 
 #[derive(Debug)]
@@ -11,14 +22,33 @@ pub enum Tx {
 
 #[derive(Debug)]
 pub enum Rx {
-    Attached,
-    Detached,
+    /// The proxy is attached to the event loop with the id.
+    Attached { id: Id },
+    /// The proxy is detached from the event loop.
+    Detached { reason: DetachReason },
+    /// The proxy is dropped, this is the last message from it.
+    Closed,
+    /// Error that the proxy has survived.
+    Error(::Error),
+    Panicked(String),
+}
+
+/// Why the proxy is detached.
+#[derive(Debug)]
+pub enum DetachReason {
+    /// The proxy is closed by the handle or by itself.
     Closed,
+    /// The proxy is taken from the event loop by the driver or the event loop is dropped.
+    Requested,
+    /// The proxy has failed with the error.
+    Error(::Error),
 }
 
 pub trait TxExt: From<Tx> + Into<Result<Tx, Self>> {}
 pub trait RxExt: From<Rx> + Into<Result<Rx, Self>> {}
 
+pub use mdrv_derive::{TxExt, RxExt};
+
 impl Into<Result<Tx, Self>> for Tx {
     fn into(self) -> Result<Tx, Self> {
         Ok(self)
@@ -35,19 +65,93 @@ impl TxExt for Tx {}
 impl RxExt for Rx {}
 
 
+/// Proxy and its handle as returned by the `create*` functions.
+pub type Pair<P, H, T, R> = (ProxyWrapper<P, T, R>, Handle<H, T, R>);
+
+/// Identifier that correlates the request with its reply.
+pub type RequestId = u64;
+
+/// Message to the proxy that could be sent as a request by `Handle::call`.
+pub trait Request {
+    /// Sets the id the proxy should put into the reply.
+    ///
+    /// Returns `false` if the message is not a request, so no reply to it is expected.
+    fn set_request_id(&mut self, id: RequestId) -> bool;
+}
+
+/// Message from the proxy that could be a reply to the request.
+pub trait Reply {
+    /// Id of the request this message replies to, or `None` if it is a notification.
+    fn request_id(&self) -> Option<RequestId>;
+}
+
+
 pub trait UserProxy<T: TxExt, R: RxExt>: Proxy {
     fn process_channel(&mut self, ctrl: &mut Control, msg: T) -> ::Result<()>;
+
+    /// Called when the handle and all its senders are dropped, the proxy is closed by default.
+    fn disconnected(&mut self, ctrl: &mut Control) -> ::Result<()> {
+        ctrl.close();
+        Ok(())
+    }
 }
 
+/// Marker of the user proxies that never block on the full channel to the handle.
+///
+/// Such a proxy sends with `Sender::try_send` and keeps the rest until the channel gets free space,
+/// which is signalled by the writable readiness of the sender registered in the event loop.
+/// Only these proxies could be created with bounded channels by `create_bounded` and `create_with_bounded`.
+pub trait NonBlocking {}
+
 pub struct ProxyWrapper<P: UserProxy<T, R>, T: TxExt, R: RxExt> {
     pub user: P,
     pub tx: Sender<R>,
     pub rx: Receiver<T>,
+    /// Reason of the upcoming detachment.
+    reason: Option<DetachReason>,
 }
 
 impl<P: UserProxy<T, R>, T: TxExt, R: RxExt> ProxyWrapper<P, T, R> {
     fn new(user: P, tx: Sender<R>, rx: Receiver<T>) -> ProxyWrapper<P, T, R> {
-        ProxyWrapper { user, tx, rx }
+        ProxyWrapper { user, tx, rx, reason: None }
+    }
+
+    fn check_closed(&mut self, ctrl: &Control) {
+        if ctrl.closed && self.reason.is_none() {
+            self.reason = Some(DetachReason::Closed);
+        }
+    }
+
+    fn process_eid(&mut self, ctrl: &mut Control, readiness: mio::Ready, eid: Eid) -> ::Result<()> {
+        match eid {
+            0 => {
+                assert!(readiness.is_readable());
+                loop {
+                    match self.rx.try_recv() {
+                        Ok(msg) => {
+                            let umsg = match msg.into() {
+                                Ok(bmsg) => {
+                                    match bmsg {
+                                        Tx::Close => ctrl.close(),
+                                    }
+                                    bmsg.into()
+                                },
+                                Err(umsg) => umsg,
+                            };
+                            match self.user.process_channel(ctrl, umsg) {
+                                Ok(()) => (),
+                                Err(e) => break Err(e),
+                            }
+                        },
+                        Err(err) => match err {
+                            TryRecvError::Empty => break Ok(()),
+                            TryRecvError::Disconnected => break self.user.disconnected(ctrl),
+                        }
+                    }
+                }
+            },
+            other_eid => self.user.process(ctrl, readiness, other_eid),
+        }
     }
 }
 
@@ -57,7 +161,7 @@ impl<P: UserProxy<T, R>, T: TxExt, R: RxExt> Proxy for ProxyWrapper<P, T, R> {
         .and_then(|_| {
             self.user.attach(ctrl)
             .and_then(|_| {
-                self.tx.send(Rx::Attached.into()).map_err(|e| ::Error::Channel(e.into()))
+                self.tx.force_send(Rx::Attached { id: ctrl.id() }.into()).map_err(|e| ::Error::Channel(e.into()))
                 .and_then(|_| {
                     Ok(())
                 })
@@ -76,7 +180,8 @@ impl<P: UserProxy<T, R>, T: TxExt, R: RxExt> Proxy for ProxyWrapper<P, T, R> {
         self.user.detach(ctrl)
         .and_then(|_| { ctrl.deregister(&self.rx) })
         .and_then(|_| {
-            match self.tx.send(Rx::Detached.into()) {
+            let reason = self.reason.take().unwrap_or(DetachReason::Requested);
+            match self.tx.force_send(Rx::Detached { reason }.into()) {
                 Ok(()) => Ok(()),
                 Err(err) => match err {
                     SendError::Disconnected(_) => Ok(()),
@@ -87,41 +192,44 @@ impl<P: UserProxy<T, R>, T: TxExt, R: RxExt> Proxy for ProxyWrapper<P, T, R> {
     }
 
     fn process(&mut self, ctrl: &mut Control, readiness: mio::Ready, eid: Eid) -> ::Result<()> {
-        match eid {
-            0 => {
-                assert!(readiness.is_readable());
-                loop {
-                    match self.rx.try_recv() {
-                        Ok(msg) => {
-                            let umsg = match msg.into() {
-                                Ok(bmsg) => {
-                                    match bmsg {
-                                        Tx::Close => ctrl.close(),
-                                    }
-                                    bmsg.into()
-                                },
-                                Err(umsg) => umsg,
-                            };
-                            match self.user.process_channel(ctrl, umsg) {
-                                Ok(()) => (),
-                                Err(e) => break Err(e),
-                            }
-                        },
-                        Err(err) => match err {
-                            TryRecvError::Empty => break Ok(()),
-                            TryRecvError::Disconnected => break Err(channel::Error::Disconnected.into()),
-                        }
-                    }
-                }
+        let res = self.process_eid(ctrl, readiness, eid);
+        self.check_closed(ctrl);
+        res
+    }
+
+    fn timeout(&mut self, ctrl: &mut Control, tid: TimerId) -> ::Result<()> {
+        let res = self.user.timeout(ctrl, tid);
+        self.check_closed(ctrl);
+        res
+    }
+
+    fn error(&mut self, _ctrl: &Control, err: ::Error) {
+        match self.user.error_policy() {
+            // The error is reported on detachment
+            ErrorPolicy::Detach | ErrorPolicy::Restart => self.reason = Some(DetachReason::Error(err)),
+            // Nothing to do if the handle is already gone
+            ErrorPolicy::Ignore => {
+                let _ = self.tx.force_send(Rx::Error(err).into());
             },
-            other_eid => self.user.process(ctrl, readiness, other_eid),
         }
     }
+
+    fn panicked(&mut self, msg: String) {
+        let _ = self.tx.force_send(Rx::Panicked(msg).into());
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        self.user.error_policy()
+    }
 }
 
 impl<P: UserProxy<T, R>, T: TxExt, R: RxExt> Drop for ProxyWrapper<P, T, R> {
     fn drop(&mut self) {
-        match self.tx.send(Rx::Closed.into()) {
+        // The error occured when the proxy was not attached, e.g. in `attach` or `detach`
+        if let Some(DetachReason::Error(err)) = self.reason.take() {
+            let _ = self.tx.force_send(Rx::Error(err).into());
+        }
+        match self.tx.force_send(Rx::Closed.into()) {
             Ok(()) => (),
             Err(err) => match err {
                 SendError::Disconnected(_) => (),
@@ -141,11 +249,24 @@ pub struct Handle<H: UserHandle<T, R>, T: TxExt, R: RxExt> {
     pub tx: Sender<T>,
     pub rx: Receiver<R>,
     closed: bool,
+    next_request: RequestId,
+    poll: Option<SinglePoll>,
 }
 
 impl<H: UserHandle<T, R>, T: TxExt, R: RxExt> Handle<H, T, R> {
     fn new(user: H, tx: Sender<T>, rx: Receiver<R>) -> Self {
-        Handle { user, tx, rx, closed: false }
+        Handle { user, tx, rx, closed: false, next_request: 0, poll: None }
+    }
+
+    /// Poll the handle receiver is registered in, created on the first use.
+    ///
+    /// The receiver could be registered in one poll only,
+    /// so this poll should be used instead of creating a separate one when `call` is used.
+    pub fn poll(&mut self) -> ::Result<&mut SinglePoll> {
+        if self.poll.is_none() {
+            self.poll = Some(SinglePoll::new(&self.rx)?);
+        }
+        Ok(self.poll.as_mut().unwrap())
     }
 
     fn is_exit(msg: R) -> (R, bool) {
@@ -168,20 +289,7 @@ impl<H: UserHandle<T, R>, T: TxExt, R: RxExt> Handle<H, T, R> {
 
         loop {
             match self.rx.try_recv() {
-                Ok(msg) => {
-                    let (msg, exit) = Self::is_exit(msg);
-                    match self.user.process_channel(msg) {
-                        Ok(()) => {
-                            if exit {
-                                self.closed = true;
-                                break Err(proxy::Error::Closed.into());
-                            } else {
-                                continue;
-                            }
-                        },
-                        Err(e) => break Err(e),
-                    }
-                },
+                Ok(msg) => self.dispatch(msg)?,
                 Err(err) => match err {
                     TryRecvError::Empty => break Ok(()),
                     TryRecvError::Disconnected => break Err(::Error::from(channel::Error::Disconnected)),
@@ -190,20 +298,173 @@ impl<H: UserHandle<T, R>, T: TxExt, R: RxExt> Handle<H, T, R> {
         }
     }
 
+    fn dispatch(&mut self, msg: R) -> ::Result<()> {
+        let (msg, exit) = Self::is_exit(msg);
+        self.user.process_channel(msg)?;
+        if exit {
+            self.closed = true;
+            Err(proxy::Error::Closed.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends the request and waits for the reply to it.
+    ///
+    /// Other messages received meanwhile are passed to `UserHandle::process_channel` as in `process`.
+    /// Returns `Error::Timeout` if the request could not be sent to the full bounded channel
+    /// or the reply is not received in `timeout`, the late reply is then passed to `UserHandle::process_channel`.
+    /// The message that is not a request is not sent and `proxy::Error::NotRequest` is returned.
+    pub fn call(&mut self, mut msg: T, timeout: Option<Duration>) -> ::Result<R> where T: Request, R: Reply {
+        if self.closed {
+            return Err(proxy::Error::Closed.into());
+        }
+        let id = self.next_request;
+        if !msg.set_request_id(id) {
+            return Err(proxy::Error::NotRequest.into());
+        }
+        self.next_request = id.wrapping_add(1);
+
+        let deadline = timeout.map(|t| Instant::now() + t);
+        match self.tx.send_timeout(msg, channel::remaining(deadline)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => return Err(::Error::Timeout),
+            Err(e) => return Err(::Error::Channel(e.into())),
+        }
+        loop {
+            match self.rx.try_recv() {
+                Ok(msg) => {
+                    if msg.request_id() == Some(id) {
+                        break Ok(msg);
+                    }
+                    self.dispatch(msg)?;
+                },
+                Err(TryRecvError::Empty) => self.wait_ready(deadline)?,
+                Err(TryRecvError::Disconnected) => break Err(channel::Error::Disconnected.into()),
+            }
+        }
+    }
+
+    /// Waits for the receiver readiness until the deadline.
+    fn wait_ready(&mut self, deadline: Option<Instant>) -> ::Result<()> {
+        match self.poll()?.wait(channel::remaining(deadline)) {
+            Ok(()) => Ok(()),
+            Err(RecvError::Empty) => Err(::Error::Timeout),
+            Err(e) => Err(::Error::Channel(e.into())),
+        }
+    }
+
+    /// Waits for at least one message and processes all received messages.
+    ///
+    /// Returns `Error::Timeout` if nothing is received in `timeout`, `None` means infinity.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> ::Result<()> {
+        if self.closed {
+            return Err(proxy::Error::Closed.into());
+        }
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            match self.rx.try_recv() {
+                Ok(msg) => {
+                    self.dispatch(msg)?;
+                    break self.process();
+                },
+                Err(TryRecvError::Empty) => self.wait_ready(deadline)?,
+                Err(TryRecvError::Disconnected) => break Err(channel::Error::Disconnected.into()),
+            }
+        }
+    }
+
+    /// Processes received messages until `pred` on the user handle returns `true`.
+    ///
+    /// The predicate is checked before waiting, so it could be already satisfied by processed messages.
+    /// Returns `Error::Timeout` if the predicate is not satisfied in `timeout`, `None` means infinity.
+    pub fn wait_until<F: FnMut(&mut H) -> bool>(&mut self, mut pred: F, timeout: Option<Duration>) -> ::Result<()> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            let res = self.process();
+            // Messages processed before the proxy is closed may satisfy the predicate
+            if pred(&mut self.user) {
+                break Ok(());
+            }
+            res?;
+            self.wait_ready(deadline)?;
+        }
+    }
+
+    /// Processes received messages until the proxy is closed.
+    ///
+    /// Returns `Error::Timeout` if the proxy is not closed in `timeout`, `None` means infinity.
+    pub fn wait_close(&mut self, timeout: Option<Duration>) -> ::Result<()> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            match self.process() {
+                Ok(()) => self.wait_ready(deadline)?,
+                Err(::Error::Proxy(proxy::Error::Closed)) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        }
+    }
+
     pub fn close(&mut self) -> ::Result<()> {
         if self.closed {
             return Err(proxy::Error::Closed.into());
         }
-        self.tx.send(Tx::Close.into()).map_err(|e| ::Error::Channel(e.into()))
+        self.tx.force_send(Tx::Close.into()).map_err(|e| ::Error::Channel(e.into()))
+    }
+
+    /// Sends the message waiting up to `timeout` while the bounded channel is full, `None` means infinity.
+    ///
+    /// Returns `channel::Error::Full` on timeout, use `tx.send_timeout` to get the message back.
+    pub fn send_timeout(&self, msg: T, timeout: Option<Duration>) -> ::Result<()> {
+        if self.closed {
+            return Err(proxy::Error::Closed.into());
+        }
+        self.tx.send_timeout(msg, timeout).map_err(|e: TrySendError<T>| ::Error::Channel(e.into()))
     }
 
     pub fn is_closed(&self) -> bool {
         self.closed
     }
+
+    /// Creates a sender that could be cloned and moved to other threads to send messages to the proxy.
+    pub fn sender(&self) -> HandleSender<T> {
+        HandleSender { tx: self.tx.clone() }
+    }
+
+    /// Subscribes to the messages sent by the proxy to the handle.
+    ///
+    /// The `filter` is called by the proxy with each message and returns its copy for the subscriber or `None` to skip it.
+    /// The subscriber queue holds at most `bound` messages, the messages that don't fit are lost.
+    pub fn subscribe<S, F>(&self, bound: usize, mut filter: F) -> ::Result<Subscription<S>>
+    where S: Send + 'static, F: FnMut(&R) -> Option<S> + Send + 'static {
+        let (tx, rx) = sync_channel(bound);
+        let lost = Arc::new(AtomicUsize::new(0));
+        let tap_lost = lost.clone();
+        self.rx.tap(move |msg| {
+            match filter(msg) {
+                Some(copy) => match tx.try_send(copy) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        tap_lost.fetch_add(1, Ordering::Relaxed);
+                        true
+                    },
+                    // The subscription is dropped
+                    Err(_) => false,
+                },
+                None => true,
+            }
+        })?;
+        Ok(Subscription { rx, lost, poll: None })
+    }
 }
 
 impl<H: UserHandle<T, R>, T: TxExt, R: RxExt> Drop for Handle<H, T, R> {
     fn drop(&mut self) {
+        // The proxy is closed on the last sender drop, its messages are discarded until then
+        if self.tx.sender_count() > 1 {
+            self.rx.discard_on_drop();
+            return;
+        }
         match self.close() {
             Ok(_) => (),
             Err(err) => match err {
@@ -215,7 +476,259 @@ impl<H: UserHandle<T, R>, T: TxExt, R: RxExt> Drop for Handle<H, T, R> {
     }
 }
 
-pub fn create<P, H, T, R>(user_proxy: P, user_handle: H) -> ::Result<(ProxyWrapper<P, T, R>, Handle<H, T, R>)>
+/// Sending half of the handle.
+///
+/// The proxy is not closed on the handle drop while any of its senders is alive,
+/// it is notified by `UserProxy::disconnected` when the last one is dropped.
+/// Messages from the proxy are discarded after the handle drop, the proxy doesn't get errors sending them.
+pub struct HandleSender<T: TxExt> {
+    pub tx: Sender<T>,
+}
+
+impl<T: TxExt> HandleSender<T> {
+    /// Sends the message waiting up to `timeout` while the bounded channel is full, `None` means infinity.
+    pub fn send_timeout(&self, msg: T, timeout: Option<Duration>) -> ::Result<()> {
+        self.tx.send_timeout(msg, timeout).map_err(|e: TrySendError<T>| ::Error::Channel(e.into()))
+    }
+
+    /// Asks the proxy to close.
+    pub fn close(&self) -> ::Result<()> {
+        self.tx.force_send(Tx::Close.into()).map_err(|e| ::Error::Channel(e.into()))
+    }
+}
+
+impl<T: TxExt> Clone for HandleSender<T> {
+    fn clone(&self) -> Self {
+        HandleSender { tx: self.tx.clone() }
+    }
+}
+
+/// Copies of the messages sent by the proxy to the handle.
+///
+/// The subscription doesn't affect the proxy and the handle, dropping it unsubscribes.
+/// It is disconnected when the proxy is dropped.
+pub struct Subscription<S> {
+    pub rx: Receiver<S>,
+    lost: Arc<AtomicUsize>,
+    poll: Option<SinglePoll>,
+}
+
+impl<S> Subscription<S> {
+    /// Receives the message waiting for it up to `timeout`, `None` means infinity.
+    ///
+    /// Returns `Error::Timeout` if nothing is received in `timeout`.
+    pub fn recv(&mut self, timeout: Option<Duration>) -> ::Result<S> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            match self.rx.try_recv() {
+                Ok(msg) => break Ok(msg),
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => break Err(channel::Error::Disconnected.into()),
+            }
+            if self.poll.is_none() {
+                self.poll = Some(SinglePoll::new(&self.rx)?);
+            }
+            match self.poll.as_mut().unwrap().wait(channel::remaining(deadline)) {
+                Ok(()) => (),
+                Err(RecvError::Empty) => break Err(::Error::Timeout),
+                Err(e) => break Err(::Error::Channel(e.into())),
+            }
+        }
+    }
+
+    /// Number of messages lost because the subscriber queue was full.
+    pub fn lost(&self) -> usize {
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
+/// Set of handles waited for at once with a single poll.
+///
+/// Handles are addressed by the key returned from `insert`.
+/// Keys of removed handles are reused.
+///
+/// The receiver of the handle in the set is registered in the set's poll,
+/// so methods of the handle that wait on its own poll (`call`, `wait`, `wait_until` and `wait_close`)
+/// fail on the handle got by `get_mut`, they should be used within `with` instead.
+pub struct HandleSet<H: UserHandle<T, R>, T: TxExt, R: RxExt> {
+    handles: Vec<Option<Handle<H, T, R>>>,
+    poll: MultiPoll,
+}
+
+impl<H: UserHandle<T, R>, T: TxExt, R: RxExt> HandleSet<H, T, R> {
+    pub fn new() -> ::Result<Self> {
+        Ok(HandleSet { handles: Vec::new(), poll: MultiPoll::new()? })
+    }
+
+    /// Deregisters the receiver from the own poll of the handle, it could be registered in one poll only.
+    fn release_poll(handle: &mut Handle<H, T, R>) -> ::Result<()> {
+        if let Some(sp) = handle.poll.take() {
+            sp.poll.deregister(&handle.rx).map_err(|e| ::Error::Channel(channel::Error::Io(e)))?;
+        }
+        Ok(())
+    }
+
+    /// Adds the handle to the set and returns its key, the handle is given back if it could not be registered.
+    #[allow(clippy::result_large_err)]
+    pub fn insert(&mut self, mut handle: Handle<H, T, R>) -> Result<usize, (Handle<H, T, R>, ::Error)> {
+        if let Err(e) = Self::release_poll(&mut handle) {
+            return Err((handle, e));
+        }
+        let key = match self.handles.iter().position(|h| h.is_none()) {
+            Some(key) => key,
+            None => {
+                self.handles.push(None);
+                self.handles.len() - 1
+            },
+        };
+        if let Err(e) = self.poll.register(&handle.rx, key) {
+            return Err((handle, e.into()));
+        }
+        self.handles[key] = Some(handle);
+        Ok(key)
+    }
+
+    /// Removes the handle from the set, returns `IdError::Missing` if there is no handle with the key.
+    pub fn remove(&mut self, key: usize) -> ::Result<Handle<H, T, R>> {
+        let slot = self.handles.get_mut(key).ok_or(IdError::Missing)?;
+        // The handle is kept in the set if it could not be deregistered
+        self.poll.deregister(&slot.as_ref().ok_or(IdError::Missing)?.rx)?;
+        Ok(slot.take().unwrap())
+    }
+
+    /// Calls `f` with the handle temporarily registered in its own poll,
+    /// so it could wait for the messages by `call`, `wait`, `wait_until` and `wait_close`.
+    ///
+    /// Returns `IdError::Missing` if there is no handle with the key.
+    pub fn with<U, F: FnOnce(&mut Handle<H, T, R>) -> U>(&mut self, key: usize, f: F) -> ::Result<U> {
+        let handle = self.handles.get_mut(key).and_then(|h| h.as_mut()).ok_or(IdError::Missing)?;
+        self.poll.deregister(&handle.rx)?;
+        // The handle is returned to the set even if `f` panics
+        let mut guard = Lend { poll: &self.poll, handle, key, done: false };
+        let res = f(guard.handle);
+        guard.restore()?;
+        Ok(res)
+    }
+
+    pub fn get(&self, key: usize) -> Option<&Handle<H, T, R>> {
+        self.handles.get(key).and_then(|h| h.as_ref())
+    }
+
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut Handle<H, T, R>> {
+        self.handles.get_mut(key).and_then(|h| h.as_mut())
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.iter().filter(|h| h.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits for messages to any of the handles and processes them.
+    ///
+    /// Returns keys of the handles that have received messages along with results of `Handle::process` for them.
+    /// Returns `Error::Timeout` if nothing is received in `timeout`, `None` means infinity.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> ::Result<Vec<(usize, ::Result<()>)>> {
+        let keys = match self.poll.wait(timeout) {
+            Ok(keys) => keys,
+            Err(RecvError::Empty) => return Err(::Error::Timeout),
+            Err(e) => return Err(::Error::Channel(e.into())),
+        };
+        Ok(keys.into_iter().filter_map(|key| {
+            // The handle could be removed after the event had been emitted
+            self.get_mut(key).map(|h| (key, h.process()))
+        }).collect())
+    }
+}
+
+/// Handle taken out of the `HandleSet` poll by `HandleSet::with`.
+struct Lend<'a, H: UserHandle<T, R> + 'a, T: TxExt + 'a, R: RxExt + 'a> {
+    poll: &'a MultiPoll,
+    handle: &'a mut Handle<H, T, R>,
+    key: usize,
+    done: bool,
+}
+
+impl<'a, H: UserHandle<T, R>, T: TxExt, R: RxExt> Lend<'a, H, T, R> {
+    /// Moves the handle from its own poll back to the set poll.
+    fn restore(&mut self) -> ::Result<()> {
+        self.done = true;
+        // Registration is tried even if the own poll could not be released
+        let released = HandleSet::release_poll(self.handle);
+        self.poll.register(&self.handle.rx, self.key)?;
+        released
+    }
+}
+
+impl<'a, H: UserHandle<T, R>, T: TxExt, R: RxExt> Drop for Lend<'a, H, T, R> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.restore();
+        }
+    }
+}
+
+/// Messages received by the handle, the stream ends after `Rx::Closed`.
+///
+/// Messages are returned directly and not passed to `UserHandle::process_channel`.
+#[cfg(feature = "futures")]
+impl<H: UserHandle<T, R> + Unpin, T: TxExt, R: RxExt> Stream for Handle<H, T, R> {
+    type Item = R;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<Option<R>> {
+        let this = self.get_mut();
+        if this.closed {
+            return task::Poll::Ready(None);
+        }
+        match Pin::new(&mut this.rx).poll_next(cx) {
+            task::Poll::Ready(Some(msg)) => {
+                let (msg, exit) = Self::is_exit(msg);
+                this.closed = exit;
+                task::Poll::Ready(Some(msg))
+            },
+            task::Poll::Ready(None) => {
+                this.closed = true;
+                task::Poll::Ready(None)
+            },
+            task::Poll::Pending => task::Poll::Pending,
+        }
+    }
+}
+
+/// Messages sent to the proxy, closing the sink closes the proxy.
+#[cfg(feature = "futures")]
+impl<H: UserHandle<T, R> + Unpin, T: TxExt, R: RxExt> Sink<T> for Handle<H, T, R> {
+    type Error = ::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<::Result<()>> {
+        if self.closed {
+            return task::Poll::Ready(Err(proxy::Error::Closed.into()));
+        }
+        Pin::new(&mut self.get_mut().tx).poll_ready(cx).map_err(::Error::Channel)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: T) -> ::Result<()> {
+        if self.closed {
+            return Err(proxy::Error::Closed.into());
+        }
+        Pin::new(&mut self.get_mut().tx).start_send(msg).map_err(::Error::Channel)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> task::Poll<::Result<()>> {
+        task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> task::Poll<::Result<()>> {
+        task::Poll::Ready(match self.get_mut().close() {
+            Err(::Error::Proxy(proxy::Error::Closed)) => Ok(()),
+            other => other,
+        })
+    }
+}
+
+pub fn create<P, H, T, R>(user_proxy: P, user_handle: H) -> ::Result<Pair<P, H, T, R>>
 where P: UserProxy<T, R>, H: UserHandle<T, R>, T: TxExt, R: RxExt {
     let (ptx, hrx) = channel();
     let (htx, prx) = channel();
@@ -224,16 +737,57 @@ where P: UserProxy<T, R>, H: UserHandle<T, R>, T: TxExt, R: RxExt {
     Ok((proxy, handle))
 }
 
+/// Same as `create` but the user proxy is constructed from the sender to the handle,
+/// so it can send its own messages to the handle.
+pub fn create_with<P, H, T, R, F>(make_proxy: F, user_handle: H) -> ::Result<Pair<P, H, T, R>>
+where P: UserProxy<T, R>, H: UserHandle<T, R>, T: TxExt, R: RxExt, F: FnOnce(Sender<R>) -> P {
+    let (ptx, hrx) = channel();
+    let (htx, prx) = channel();
+    let proxy = ProxyWrapper::new(make_proxy(ptx.clone()), ptx, prx);
+    let handle = Handle::new(user_handle, htx, hrx);
+    Ok((proxy, handle))
+}
+
+/// Same as `create` but channels in both directions hold at most `bound` messages.
+///
+/// Service messages like `Tx::Close` and `Rx::Closed` are not limited by the bound.
+pub fn create_bounded<P, H, T, R>(user_proxy: P, user_handle: H, bound: usize) -> ::Result<Pair<P, H, T, R>>
+where P: UserProxy<T, R> + NonBlocking, H: UserHandle<T, R>, T: TxExt, R: RxExt {
+    create_with_bounded(|_| user_proxy, user_handle, bound)
+}
+
+/// Same as `create_with` but channels in both directions hold at most `bound` messages.
+///
+/// The proxy runs on the event loop thread and so must not block on the full channel, see `NonBlocking`.
+pub fn create_with_bounded<P, H, T, R, F>(make_proxy: F, user_handle: H, bound: usize) -> ::Result<Pair<P, H, T, R>>
+where P: UserProxy<T, R> + NonBlocking, H: UserHandle<T, R>, T: TxExt, R: RxExt, F: FnOnce(Sender<R>) -> P {
+    let (ptx, hrx) = sync_channel(bound);
+    let (htx, prx) = sync_channel(bound);
+    let proxy = ProxyWrapper::new(make_proxy(ptx.clone()), ptx, prx);
+    let handle = Handle::new(user_handle, htx, hrx);
+    Ok((proxy, handle))
+}
+
 
 #[cfg(test)]
 mod test {
     use super::*;
 
     use ::channel::{SinglePoll, PollReceiver, RecvError};
+    use ::timer::{Timers};
+    use ::token::{Tokens};
+
+    use std::cell::{RefCell};
 
     use std::thread;
+    use std::panic::{self, AssertUnwindSafe};
+
+    use ::dummy::{self, DummyHandle};
+    use ::event_loop::{EventLoop};
 
-    use ::dummy;
+    use std::collections::{VecDeque};
+    use std::sync::{Arc};
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     #[test]
     fn handle_close_after() {
@@ -241,12 +795,12 @@ mod test {
 
         assert_matches!(h.close(), Err(::Error::Channel(channel::Error::Disconnected)));
 
-        assert_eq!(h.is_closed(), false);
+        assert!(!h.is_closed());
 
         assert_matches!(h.process(), Err(::Error::Proxy(proxy::Error::Closed)));
         assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
         assert_matches!(h.user.msgs.pop_front(), None);
-        assert_eq!(h.is_closed(), true);
+        assert!(h.is_closed());
 
         assert_matches!(h.rx.try_recv(), Err(TryRecvError::Disconnected));
     }
@@ -264,7 +818,7 @@ mod test {
         h.process().unwrap();
 
         h.close().unwrap();
-        assert_eq!(h.is_closed(), false);
+        assert!(!h.is_closed());
 
         let mut sp = SinglePoll::new(&h.rx).unwrap();
         sp.wait(None).unwrap();
@@ -272,7 +826,370 @@ mod test {
 
         assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
         assert_matches!(h.user.msgs.pop_front(), None);
-        assert_eq!(h.is_closed(), true);
+        assert!(h.is_closed());
+    }
+
+    #[test]
+    fn proxy_error() {
+        let (mut p, mut h) = dummy::create().unwrap();
+        let poll = mio::Poll::new().unwrap();
+        let timers = RefCell::new(Timers::new());
+        let tokens = RefCell::new(Tokens::new());
+
+        p.error(&Control::new(1, &poll, &timers, &tokens), proxy::Error::Closed.into());
+        assert_eq!(p.error_policy(), ErrorPolicy::Detach);
+
+        // the error is kept until detachment or drop
+        h.process().unwrap();
+        assert_matches!(h.user.msgs.pop_front(), None);
+
+        drop(p);
+        assert_matches!(h.process(), Err(::Error::Proxy(proxy::Error::Closed)));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Error(::Error::Proxy(proxy::Error::Closed))));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
+    }
+
+    struct FailProxy {
+        policy: ErrorPolicy,
+    }
+
+    impl Proxy for FailProxy {
+        fn attach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn detach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn process(&mut self, _ctrl: &mut Control, _readiness: mio::Ready, _eid: Eid) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn error_policy(&self) -> ErrorPolicy {
+            self.policy
+        }
+    }
+
+    impl UserProxy<Tx, Rx> for FailProxy {
+        fn process_channel(&mut self, _ctrl: &mut Control, _msg: Tx) -> ::Result<()> {
+            Err(::std::io::Error::other("fail").into())
+        }
+    }
+
+    fn run_until_closed(el: &mut EventLoop, h: &mut Handle<DummyHandle, Tx, Rx>) {
+        while !h.is_closed() {
+            el.run_once(Some(Duration::from_millis(10))).unwrap();
+            let _ = h.process();
+        }
+    }
+
+    struct DropProxy {
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Proxy for DropProxy {
+        fn attach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn detach(&mut self, _ctrl: &Control) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn process(&mut self, _ctrl: &mut Control, _readiness: mio::Ready, _eid: Eid) -> ::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl UserProxy<Tx, Rx> for DropProxy {
+        fn process_channel(&mut self, _ctrl: &mut Control, _msg: Tx) -> ::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for DropProxy {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn sender() {
+        let mut el = EventLoop::new().unwrap();
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let (p, h) = create(DropProxy { dropped: dropped.clone() }, DummyHandle { msgs: VecDeque::new() }).unwrap();
+        el.attach(Box::new(p)).unwrap();
+
+        let s = h.sender();
+        let s2 = s.clone();
+        thread::spawn(move || drop(s2)).join().unwrap();
+        drop(h);
+        el.run_once(Some(Duration::from_millis(10))).unwrap();
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        drop(s);
+        while !dropped.load(Ordering::SeqCst) {
+            el.run_once(Some(Duration::from_millis(10))).unwrap();
+        }
+
+        let (p, mut h) = dummy::create().unwrap();
+        el.attach(Box::new(p)).unwrap();
+        let s = h.sender();
+        thread::spawn(move || s.close().unwrap()).join().unwrap();
+        run_until_closed(&mut el, &mut h);
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Attached { .. }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Detached { reason: DetachReason::Closed }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
+    }
+
+    #[test]
+    fn subscribe() {
+        let mut el = EventLoop::new().unwrap();
+        let (p, mut h) = dummy::create().unwrap();
+
+        let mut all = h.subscribe(1, |_| Some(())).unwrap();
+        let mut ids = h.subscribe(8, |msg| match *msg {
+            Rx::Attached { id } => Some(id),
+            _ => None,
+        }).unwrap();
+        let dropped = h.subscribe(8, |_| Some(())).unwrap();
+        drop(dropped);
+
+        let id = el.attach(Box::new(p)).unwrap();
+        assert_eq!(ids.recv(Some(Duration::from_secs(10))).unwrap(), id);
+        h.close().unwrap();
+        run_until_closed(&mut el, &mut h);
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Attached { .. }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Detached { reason: DetachReason::Closed }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
+
+        assert_matches!(all.recv(Some(Duration::from_secs(10))), Ok(()));
+        assert_eq!(all.lost(), 2);
+        assert_matches!(all.recv(Some(Duration::from_secs(10))), Err(::Error::Channel(channel::Error::Disconnected)));
+        assert_matches!(ids.recv(Some(Duration::from_secs(10))), Err(::Error::Channel(channel::Error::Disconnected)));
+        assert_matches!(h.subscribe(8, |_| Some(())).err(), Some(::Error::Channel(channel::Error::Disconnected)));
+    }
+
+    #[test]
+    fn handle_set() {
+        let mut el = EventLoop::new().unwrap();
+        let mut hs = HandleSet::new().unwrap();
+        assert!(hs.is_empty());
+
+        let mut keys = Vec::new();
+        for i in 0..3 {
+            let (p, mut h) = dummy::create().unwrap();
+            if i == 0 {
+                // the handle has already used its own poll
+                assert_matches!(h.wait(Some(Duration::from_millis(1))), Err(::Error::Timeout));
+            }
+            el.attach(Box::new(p)).unwrap();
+            keys.push(hs.insert(h).ok().unwrap());
+        }
+        assert_eq!(keys, vec![0, 1, 2]);
+        assert_eq!(hs.len(), 3);
+
+        let mut ready = Vec::new();
+        while ready.len() < 3 {
+            for (key, res) in hs.wait(Some(Duration::from_secs(10))).unwrap() {
+                res.unwrap();
+                ready.push(key);
+            }
+        }
+        ready.sort();
+        assert_eq!(ready, keys);
+        for &key in keys.iter() {
+            assert_matches!(hs.get_mut(key).unwrap().user.msgs.pop_front(), Some(Rx::Attached { .. }));
+        }
+        assert_matches!(hs.wait(Some(Duration::from_millis(10))), Err(::Error::Timeout));
+
+        hs.get_mut(1).unwrap().close().unwrap();
+        el.run_once(Some(Duration::from_millis(10))).unwrap();
+        let res = hs.wait(Some(Duration::from_secs(10))).unwrap();
+        assert_matches!(res[..], [(1, Err(::Error::Proxy(proxy::Error::Closed)))]);
+
+        let mut h = hs.remove(1).unwrap();
+        assert!(h.is_closed());
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Detached { reason: DetachReason::Closed }));
+        assert!(hs.get(1).is_none());
+        assert_eq!(hs.len(), 2);
+        assert_matches!(hs.remove(1).err(), Some(::Error::Id(IdError::Missing)));
+        assert_matches!(hs.remove(7).err(), Some(::Error::Id(IdError::Missing)));
+
+        // the handle waits on its own poll only within `with`
+        assert_matches!(hs.get_mut(0).unwrap().wait(Some(Duration::from_millis(1))), Err(::Error::Channel(_)));
+        hs.get_mut(0).unwrap().close().unwrap();
+        el.run_once(Some(Duration::from_millis(10))).unwrap();
+        hs.with(0, |h| h.wait_close(Some(Duration::from_secs(10)))).unwrap().unwrap();
+        assert_matches!(hs.with(7, |_| ()).err(), Some(::Error::Id(IdError::Missing)));
+        assert_matches!(hs.remove(0).unwrap().user.msgs.pop_back(), Some(Rx::Closed));
+        assert_matches!(hs.wait(Some(Duration::from_millis(10))), Err(::Error::Timeout));
+
+        let (p, h) = dummy::create().unwrap();
+        el.attach(Box::new(p)).unwrap();
+        assert_eq!(hs.insert(h).ok(), Some(0));
+        assert_matches!(hs.wait(Some(Duration::from_secs(10))).unwrap()[..], [(0, Ok(()))]);
+
+        // the set keeps waiting on the handle after `with`
+        assert_matches!(hs.with(2, |h| h.wait(Some(Duration::from_millis(1)))).unwrap(), Err(::Error::Timeout));
+        hs.get_mut(2).unwrap().close().unwrap();
+        el.run_once(Some(Duration::from_millis(10))).unwrap();
+        assert_matches!(hs.wait(Some(Duration::from_secs(10))).unwrap()[..], [(2, Err(::Error::Proxy(proxy::Error::Closed)))]);
+
+        // the handle is returned to the set poll when `with` panics
+        let res = panic::catch_unwind(AssertUnwindSafe(|| hs.with(0, |_| panic!("with"))));
+        assert!(res.is_err());
+        hs.get_mut(0).unwrap().close().unwrap();
+        el.run_once(Some(Duration::from_millis(10))).unwrap();
+        assert_matches!(hs.wait(Some(Duration::from_secs(10))).unwrap()[..], [(0, Err(::Error::Proxy(proxy::Error::Closed)))]);
+
+        // the handle that could not be registered is given back
+        let (_p, h) = dummy::create().unwrap();
+        let other = MultiPoll::new().unwrap();
+        other.register(&h.rx, 0).unwrap();
+        let h = match hs.insert(h) {
+            Err((h, ::Error::Channel(_))) => h,
+            _ => panic!(),
+        };
+        assert_eq!(hs.len(), 2);
+        other.deregister(&h.rx).unwrap();
+        assert_eq!(hs.insert(h).ok(), Some(1));
+    }
+
+    #[test]
+    fn lifecycle() {
+        let mut el = EventLoop::new().unwrap();
+
+        let (p, mut h) = dummy::create().unwrap();
+        let id = el.attach(Box::new(p)).unwrap();
+        h.close().unwrap();
+        run_until_closed(&mut el, &mut h);
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Attached { id: aid }) if aid == id);
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Detached { reason: DetachReason::Closed }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
+
+        let (p, mut h) = dummy::create().unwrap();
+        let id = el.attach(Box::new(p)).unwrap();
+        drop(el.detach(id).unwrap());
+        run_until_closed(&mut el, &mut h);
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Attached { .. }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Detached { reason: DetachReason::Requested }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
+
+        let (p, mut h) = create(FailProxy { policy: ErrorPolicy::Detach }, DummyHandle { msgs: VecDeque::new() }).unwrap();
+        el.attach(Box::new(p)).unwrap();
+        h.close().unwrap();
+        run_until_closed(&mut el, &mut h);
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Attached { .. }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Detached { reason: DetachReason::Error(::Error::Io(_)) }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
+
+        // non-fatal error is reported immediately
+        let (p, mut h) = create(FailProxy { policy: ErrorPolicy::Ignore }, DummyHandle { msgs: VecDeque::new() }).unwrap();
+        el.attach(Box::new(p)).unwrap();
+        h.close().unwrap();
+        run_until_closed(&mut el, &mut h);
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Attached { .. }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Error(::Error::Io(_))));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Detached { reason: DetachReason::Closed }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
+    }
+
+    #[test]
+    fn wait() {
+        let (p, mut h) = dummy::create().unwrap();
+
+        assert_matches!(h.wait(Some(Duration::from_millis(10))), Err(::Error::Timeout));
+        assert_matches!(h.wait_close(Some(Duration::from_millis(10))), Err(::Error::Timeout));
+
+        thread::spawn(move || {
+            let mp = p;
+            thread::sleep(Duration::from_millis(10));
+            mp.tx.send(Rx::Attached { id: 1 }).unwrap();
+            thread::sleep(Duration::from_millis(10));
+            mp.tx.send(Rx::Detached { reason: DetachReason::Requested }).unwrap();
+        });
+        h.wait(None).unwrap();
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Attached { .. }));
+        h.wait_until(|u| u.msgs.len() == 2, Some(Duration::from_secs(10))).unwrap();
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Detached { .. }));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Closed));
+
+        assert_matches!(h.wait(None), Err(::Error::Proxy(proxy::Error::Closed)));
+        h.wait_close(None).unwrap();
+    }
+
+    #[derive(Debug, TxExt)]
+    enum DerivedTx<T> {
+        Data(T),
+        #[mdrv(base)]
+        Base(Tx),
+    }
+
+    #[test]
+    fn derive() {
+        assert_matches!(DerivedTx::<u8>::from(Tx::Close), DerivedTx::Base(Tx::Close));
+        let base: Result<Tx, DerivedTx<u8>> = DerivedTx::Base(Tx::Close).into();
+        assert_matches!(base, Ok(Tx::Close));
+        let user: Result<Tx, DerivedTx<u8>> = DerivedTx::Data(1).into();
+        assert_matches!(user, Err(DerivedTx::Data(1)));
+    }
+
+    impl Request for DerivedTx<RequestId> {
+        fn set_request_id(&mut self, id: RequestId) -> bool {
+            match *self {
+                DerivedTx::Data(ref mut rid) => {
+                    *rid = id;
+                    true
+                },
+                DerivedTx::Base(_) => false,
+            }
+        }
+    }
+
+    impl Reply for Rx {
+        fn request_id(&self) -> Option<RequestId> {
+            None
+        }
+    }
+
+    struct NullHandle;
+
+    impl UserHandle<DerivedTx<RequestId>, Rx> for NullHandle {
+        fn process_channel(&mut self, _msg: Rx) -> ::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn call_full() {
+        let (htx, _prx) = sync_channel(1);
+        let (_ptx, hrx) = sync_channel(1);
+        let mut h = Handle::new(NullHandle, htx, hrx);
+
+        h.tx.send(DerivedTx::Data(0)).unwrap();
+        assert_matches!(h.call(DerivedTx::Data(0), Some(Duration::from_millis(10))), Err(::Error::Timeout));
+        assert_matches!(h.call(DerivedTx::Base(Tx::Close), None), Err(::Error::Proxy(proxy::Error::NotRequest)));
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn sink_full() {
+        use futures::task::{noop_waker};
+
+        let (htx, prx) = sync_channel(1);
+        let (_ptx, hrx) = sync_channel(1);
+        let mut h = Handle::new(NullHandle, htx, hrx);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert_matches!(Pin::new(&mut h).poll_ready(&mut cx), task::Poll::Ready(Ok(())));
+        Pin::new(&mut h).start_send(DerivedTx::Data(0)).unwrap();
+        assert!(Pin::new(&mut h).poll_ready(&mut cx).is_pending());
+        prx.try_recv().unwrap();
+        assert_matches!(Pin::new(&mut h).poll_ready(&mut cx), task::Poll::Ready(Ok(())));
     }
 
     #[test]
@@ -283,4 +1200,21 @@ mod test {
         assert_matches!(prx.recv(None), Ok(Tx::Close));
         assert_matches!(prx.recv(None), Err(RecvError::Disconnected));
     }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn stream_sink() {
+        use futures::{executor, SinkExt};
+        use ::driver::{Driver};
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = dummy::create().unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        executor::block_on(SinkExt::close(&mut h)).unwrap();
+        let msgs = executor::block_on_stream(&mut h).collect::<Vec<_>>();
+        assert_matches!(msgs[..], [Rx::Attached { .. }, Rx::Detached { reason: DetachReason::Closed }, Rx::Closed]);
+        assert!(h.is_closed());
+        assert_matches!(executor::block_on(SinkExt::send(&mut h, Tx::Close)), Err(::Error::Proxy(proxy::Error::Closed)));
+    }
 }
@@ -0,0 +1,506 @@
+//! Stream proxy that reconnects after the connection is lost.
+//!
+//! The proxy stays attached to the event loop while the connection is down.
+//! The dead stream is deregistered and a new one is connected after the delay given by [`Backoff`].
+//! Connection state changes are reported to the handle.
+//! Messages sent while disconnected are buffered and written after the connection is established
+//! and the message written in part when the connection is lost is written again from its start.
+//!
+//! [`Backoff`]: struct.Backoff.html
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr};
+use std::time::{Duration};
+use std::collections::{VecDeque};
+use std::collections::hash_map::{RandomState};
+use std::hash::{BuildHasher, Hasher};
+
+use mio::{self, Evented};
+use mio::net::{TcpStream};
+
+use ::channel::{Sender};
+use ::codec::{Codec};
+use ::proxy::{Proxy, Control, Eid, TimerId};
+use ::proxy_handle::{self, Handle, UserProxy, UserHandle, RxExt};
+use ::proxy_handle::{Rx as BaseRx};
+use ::stream_proxy::{self, Framed};
+
+pub use stream_proxy::{Tx};
+
+
+const STREAM: Eid = 1;
+
+
+#[derive(Debug, RxExt)]
+pub enum Rx<T> {
+    #[mdrv(base)]
+    Base(BaseRx),
+    /// Message decoded from the stream.
+    Recv(T),
+    /// The connection is established.
+    Connected,
+    /// The connection is lost or failed to establish.
+    Disconnected(::Error),
+    /// The next connection attempt is made after the delay.
+    Reconnecting { attempt: usize, delay: Duration },
+}
+
+
+/// Exponential backoff with jitter.
+///
+/// The delay before the `n`-th attempt is `initial * multiplier^(n - 1)` limited by `max`
+/// and then randomly reduced by up to `jitter` fraction of it.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: u32,
+    jitter: f64,
+    max_attempts: Option<usize>,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+
+    /// Delay before the first attempt.
+    pub fn initial(mut self, delay: Duration) -> Self {
+        self.initial = delay;
+        self
+    }
+
+    /// Maximum delay between attempts.
+    pub fn max(mut self, delay: Duration) -> Self {
+        self.max = delay;
+        self
+    }
+
+    /// Factor the delay is multiplied by after each failed attempt.
+    pub fn multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Fraction of the delay from 0 to 1 that is randomly subtracted.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Number of attempts after that the proxy gives up, by default it never does.
+    pub fn max_attempts(mut self, attempts: usize) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    fn base(&self, attempt: usize) -> Duration {
+        let mut delay = self.initial;
+        for _ in 1..attempt {
+            delay = match delay.checked_mul(self.multiplier) {
+                Some(d) if d < self.max => d,
+                _ => return self.max,
+            };
+        }
+        delay.min(self.max)
+    }
+
+    /// Delay before the attempt numbered from 1.
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(attempt);
+        let random = (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64;
+        self.base(attempt).mul_f64(1.0 - self.jitter*random)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Source of new streams.
+pub trait Connector {
+    type Stream: Evented + Read + Write;
+
+    /// Starts the connection, it may complete later.
+    fn connect(&mut self) -> io::Result<Self::Stream>;
+
+    /// Takes the pending error of the stream, it is checked when the stream becomes writable first.
+    fn take_error(&self, _stream: &Self::Stream) -> io::Result<Option<io::Error>> {
+        Ok(None)
+    }
+}
+
+impl Connector for SocketAddr {
+    type Stream = TcpStream;
+
+    fn connect(&mut self) -> io::Result<TcpStream> {
+        TcpStream::connect(self)
+    }
+
+    fn take_error(&self, stream: &TcpStream) -> io::Result<Option<io::Error>> {
+        stream.take_error()
+    }
+}
+
+
+pub struct ReconnectProxy<N: Connector, C: Codec> {
+    connector: N,
+    backoff: Backoff,
+    framed: Framed<C>,
+    tx: Sender<Rx<C::In>>,
+    stream: Option<N::Stream>,
+    connected: bool,
+    failures: usize,
+    timer: Option<TimerId>,
+}
+
+impl<N: Connector, C: Codec> ReconnectProxy<N, C> {
+    fn new(connector: N, codec: C, backoff: Backoff, tx: Sender<Rx<C::In>>) -> Self {
+        Self {
+            connector, backoff, tx,
+            framed: Framed::new(codec),
+            stream: None,
+            connected: false,
+            failures: 0,
+            timer: None,
+        }
+    }
+
+    fn send(&self, msg: Rx<C::In>) -> ::Result<()> {
+        self.tx.send(msg).map_err(|e| ::Error::Channel(e.into()))
+    }
+
+    fn connect(&mut self, ctrl: &Control) -> ::Result<()> {
+        match self.connector.connect() {
+            Ok(stream) => {
+                ctrl.register(&stream, STREAM, mio::Ready::readable() | mio::Ready::writable(), mio::PollOpt::edge())?;
+                self.stream = Some(stream);
+                Ok(())
+            },
+            Err(err) => self.fail(ctrl, err.into()),
+        }
+    }
+
+    /// Drops the stream and schedules the next attempt.
+    fn fail(&mut self, ctrl: &Control, err: ::Error) -> ::Result<()> {
+        if let Some(stream) = self.stream.take() {
+            ctrl.deregister_eid(&stream, STREAM)?;
+        }
+        self.connected = false;
+        self.framed.restart();
+        self.failures += 1;
+        self.send(Rx::Disconnected(err))?;
+
+        if self.backoff.max_attempts.is_some_and(|n| self.failures > n) {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "maximum number of reconnect attempts reached").into());
+        }
+        let delay = self.backoff.delay(self.failures);
+        self.send(Rx::Reconnecting { attempt: self.failures, delay })?;
+        self.timer = Some(ctrl.set_timeout(delay)?);
+        Ok(())
+    }
+
+    fn process_stream(&mut self, readiness: mio::Ready) -> ::Result<()> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        if !self.connected && readiness.is_writable() {
+            if let Some(err) = self.connector.take_error(stream)? {
+                return Err(err.into());
+            }
+        }
+        // Reading first reveals the connection failure before the stream is considered connected
+        let mut msgs = Vec::new();
+        let res = if readiness.is_readable() {
+            self.framed.fill(stream, |msg| {
+                msgs.push(msg);
+                Ok(true)
+            })
+        } else {
+            Ok(false)
+        };
+        if !self.connected && ((readiness.is_writable() && res.is_ok()) || !msgs.is_empty()) {
+            self.connected = true;
+            self.failures = 0;
+            self.tx.send(Rx::Connected).map_err(|e| ::Error::Channel(e.into()))?;
+        }
+        // Messages decoded before the read error are delivered first
+        for msg in msgs {
+            self.tx.send(Rx::Recv(msg)).map_err(|e| ::Error::Channel(e.into()))?;
+        }
+        if res? {
+            return Err(stream_proxy::closed());
+        }
+        if self.connected {
+            self.framed.flush(stream)?;
+        }
+        Ok(())
+    }
+
+    /// Turns I/O errors into reconnection.
+    fn recover(&mut self, ctrl: &Control, res: ::Result<()>) -> ::Result<()> {
+        match res {
+            Err(err @ ::Error::Io(_)) => self.fail(ctrl, err),
+            other => other,
+        }
+    }
+}
+
+impl<N: Connector, C: Codec> Proxy for ReconnectProxy<N, C> {
+    fn attach(&mut self, ctrl: &Control) -> ::Result<()> {
+        self.failures = 0;
+        // The first attempt is made from the timer, so its failure is reported after `Attached`
+        self.timer = Some(ctrl.set_timeout(Duration::from_secs(0))?);
+        Ok(())
+    }
+
+    fn detach(&mut self, ctrl: &Control) -> ::Result<()> {
+        if let Some(tid) = self.timer.take() {
+            // The timer could be already fired
+            let _ = ctrl.cancel_timer(tid);
+        }
+        self.connected = false;
+        self.framed.reset();
+        match self.stream.take() {
+            Some(stream) => ctrl.deregister_eid(&stream, STREAM),
+            None => Ok(()),
+        }
+    }
+
+    fn process(&mut self, ctrl: &mut Control, readiness: mio::Ready, _eid: Eid) -> ::Result<()> {
+        let res = self.process_stream(readiness);
+        self.recover(ctrl, res)
+    }
+
+    fn timeout(&mut self, ctrl: &mut Control, _tid: TimerId) -> ::Result<()> {
+        self.timer = None;
+        self.connect(ctrl)
+    }
+}
+
+impl<N: Connector, C: Codec> UserProxy<Tx<C::Out>, Rx<C::In>> for ReconnectProxy<N, C> {
+    fn process_channel(&mut self, ctrl: &mut Control, msg: Tx<C::Out>) -> ::Result<()> {
+        match msg {
+            Tx::Send(msg) => {
+                self.framed.encode(msg)?;
+                let res = match (self.connected, self.stream.as_mut()) {
+                    (true, Some(stream)) => self.framed.flush(stream).map_err(::Error::Io),
+                    _ => Ok(()),
+                };
+                self.recover(ctrl, res)
+            },
+            Tx::Base(_) => Ok(()),
+        }
+    }
+}
+
+pub struct ReconnectHandle<T> {
+    pub msgs: VecDeque<Rx<T>>,
+}
+
+impl<T> ReconnectHandle<T> {
+    fn new() -> Self {
+        Self { msgs: VecDeque::new() }
+    }
+}
+
+impl<T, U> UserHandle<Tx<U>, Rx<T>> for ReconnectHandle<T> {
+    fn process_channel(&mut self, msg: Rx<T>) -> ::Result<()> {
+        self.msgs.push_back(msg);
+        Ok(())
+    }
+}
+
+/// Reconnecting proxy and its handle as returned by `create`.
+pub type Pair<N, C> = proxy_handle::Pair<ReconnectProxy<N, C>, ReconnectHandle<<C as Codec>::In>, Tx<<C as Codec>::Out>, Rx<<C as Codec>::In>>;
+
+/// Creates the proxy exchanging messages framed by the `codec` over streams made by the `connector` and its handle.
+pub fn create<N, C>(connector: N, codec: C, backoff: Backoff) -> ::Result<Pair<N, C>>
+where N: Connector, C: Codec {
+    proxy_handle::create_with(|tx| ReconnectProxy::new(connector, codec, backoff, tx), ReconnectHandle::new())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+    use std::net::{TcpListener};
+
+    use ::codec::{LineCodec, LengthPrefixedCodec};
+    use ::driver::{Driver};
+    use ::proxy_handle::{DetachReason};
+
+    fn wait_msg<T, U>(h: &mut Handle<ReconnectHandle<T>, Tx<U>, Rx<T>>) -> Rx<T> {
+        h.wait_until(|u| !u.msgs.is_empty(), Some(Duration::from_secs(10))).unwrap();
+        h.user.msgs.pop_front().unwrap()
+    }
+
+    #[test]
+    fn backoff() {
+        let backoff = Backoff::new().initial(Duration::from_millis(10)).max(Duration::from_millis(100)).jitter(0.0);
+        let delays = (1..6).map(|i| backoff.delay(i).as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![10, 20, 40, 80, 100]);
+
+        let backoff = backoff.jitter(0.5);
+        for _ in 0..100 {
+            let delay = backoff.delay(2);
+            assert!(delay >= Duration::from_millis(10) && delay <= Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"first\n").unwrap();
+            drop(stream);
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"second\n").unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"ping\n");
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let backoff = Backoff::new().initial(Duration::from_millis(1));
+        let (p, mut h) = create(addr, LineCodec::default(), backoff).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        assert_matches!(wait_msg(&mut h), Rx::Recv(ref msg) if msg == b"first");
+        assert_matches!(wait_msg(&mut h), Rx::Disconnected(::Error::Io(_)));
+        assert_matches!(wait_msg(&mut h), Rx::Reconnecting { attempt: 1, .. });
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        assert_matches!(wait_msg(&mut h), Rx::Recv(ref msg) if msg == b"second");
+        h.tx.send(Tx::Send(b"ping".to_vec())).unwrap();
+        thr.join().unwrap();
+    }
+
+    /// Connector failing the attempt with the index.
+    struct Flaky {
+        addr: SocketAddr,
+        attempt: usize,
+        fail: usize,
+    }
+
+    impl Connector for Flaky {
+        type Stream = TcpStream;
+
+        fn connect(&mut self) -> io::Result<TcpStream> {
+            self.attempt += 1;
+            if self.attempt == self.fail {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "flaky"));
+            }
+            self.addr.connect()
+        }
+    }
+
+    #[test]
+    fn queued_across_retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            drop(listener.accept().unwrap());
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            let mut buf = [0; 7];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"queued\n");
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let backoff = Backoff::new().initial(Duration::from_millis(50)).jitter(0.0);
+        let (p, mut h) = create(Flaky { addr, attempt: 0, fail: 2 }, LineCodec::default(), backoff).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        assert_matches!(wait_msg(&mut h), Rx::Disconnected(::Error::Io(_)));
+        assert_matches!(wait_msg(&mut h), Rx::Reconnecting { attempt: 1, .. });
+        h.tx.send(Tx::Send(b"queued".to_vec())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Disconnected(::Error::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionRefused);
+        assert_matches!(wait_msg(&mut h), Rx::Reconnecting { attempt: 2, .. });
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn first_attempt_failed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            drop(listener.accept().unwrap());
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let backoff = Backoff::new().initial(Duration::from_millis(1));
+        let (p, mut h) = create(Flaky { addr, attempt: 0, fail: 1 }, LineCodec::default(), backoff).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        // the failure of the connection made on attachment is reported after it
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Disconnected(::Error::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionRefused);
+        assert_matches!(wait_msg(&mut h), Rx::Reconnecting { attempt: 1, .. });
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn decoded_before_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"\x00\x02hi\x01\x00").unwrap();
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let backoff = Backoff::new().initial(Duration::from_secs(10));
+        let (p, mut h) = create(addr, LengthPrefixedCodec::new(2).max_len(0x10), backoff).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        assert_matches!(wait_msg(&mut h), Rx::Recv(ref msg) if msg == b"hi");
+        assert_matches!(wait_msg(&mut h), Rx::Disconnected(::Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData);
+        assert_matches!(wait_msg(&mut h), Rx::Reconnecting { attempt: 1, .. });
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn give_up() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let mut drv = Driver::new().unwrap();
+        let backoff = Backoff::new().initial(Duration::from_millis(1)).max_attempts(2);
+        let (p, mut h) = create(addr, LineCodec::default(), backoff).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+
+        for attempt in 1..3 {
+            assert_matches!(wait_msg(&mut h), Rx::Disconnected(::Error::Io(_)));
+            assert_matches!(wait_msg(&mut h), Rx::Reconnecting { attempt: a, .. } if a == attempt);
+        }
+        assert_matches!(wait_msg(&mut h), Rx::Disconnected(::Error::Io(_)));
+        assert_matches!(
+            wait_msg(&mut h),
+            Rx::Base(BaseRx::Detached { reason: DetachReason::Error(::Error::Io(ref e)) }) if e.kind() == io::ErrorKind::NotConnected
+        );
+    }
+}
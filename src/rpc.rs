@@ -0,0 +1,293 @@
+//! ONC RPC (RFC 5531) over TCP: XDR encoding, call and reply messages and record marking.
+
+use std::io::{self};
+#[cfg(test)]
+use std::io::{Read};
+
+use mio::net::{TcpStream};
+
+use ::codec::{Codec};
+use ::stream_proxy::{Framed};
+
+
+pub const PORTMAPPER_PROG: u32 = 100000;
+pub const PORTMAPPER_VERS: u32 = 2;
+pub const PORTMAPPER_GETPORT: u32 = 3;
+pub const IPPROTO_TCP: u32 = 6;
+
+const RPC_VERS: u32 = 2;
+const CALL: u32 = 0;
+const REPLY: u32 = 1;
+const MSG_ACCEPTED: u32 = 0;
+const SUCCESS: u32 = 0;
+const AUTH_NONE: u32 = 0;
+
+const LAST_FRAGMENT: u32 = 1 << 31;
+
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// XDR encoder appending values to the buffer.
+pub struct Encoder {
+    pub buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn opaque(mut self, data: &[u8]) -> Self {
+        self = self.u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+        let pad = (4 - data.len() % 4) % 4;
+        self.buf.extend_from_slice(&[0; 3][..pad]);
+        self
+    }
+
+    pub fn string(self, value: &str) -> Self {
+        self.opaque(value.as_bytes())
+    }
+}
+
+/// XDR decoder reading values from the buffer.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(invalid("unexpected end of XDR data"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn opaque(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        let data = self.take(len)?.to_vec();
+        self.take((4 - len % 4) % 4)?;
+        Ok(data)
+    }
+
+    pub fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// Encodes the call message with null authentication.
+pub fn call(xid: u32, prog: u32, vers: u32, procedure: u32, args: &[u8]) -> Vec<u8> {
+    let mut enc = Encoder::new()
+    .u32(xid).u32(CALL).u32(RPC_VERS)
+    .u32(prog).u32(vers).u32(procedure)
+    .u32(AUTH_NONE).u32(0)
+    .u32(AUTH_NONE).u32(0);
+    enc.buf.extend_from_slice(args);
+    enc.buf
+}
+
+/// Decodes the reply message and returns its xid and the results of successfully accepted call.
+pub fn reply(data: &[u8]) -> io::Result<(u32, &[u8])> {
+    let mut dec = Decoder::new(data);
+    let xid = dec.u32()?;
+    if dec.u32()? != REPLY {
+        return Err(invalid("RPC message is not a reply"));
+    }
+    if dec.u32()? != MSG_ACCEPTED {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "RPC call denied"));
+    }
+    dec.u32()?;
+    dec.opaque()?;
+    match dec.u32()? {
+        SUCCESS => Ok((xid, dec.rest())),
+        stat => Err(io::Error::other(format!("RPC call not accepted, status {}", stat))),
+    }
+}
+
+/// Decodes the call message and returns its xid, program, version, procedure and arguments.
+#[cfg(test)]
+pub fn parse_call(data: &[u8]) -> io::Result<(u32, u32, u32, u32, &[u8])> {
+    let mut dec = Decoder::new(data);
+    let xid = dec.u32()?;
+    if dec.u32()? != CALL || dec.u32()? != RPC_VERS {
+        return Err(invalid("bad RPC call"));
+    }
+    let (prog, vers, procedure) = (dec.u32()?, dec.u32()?, dec.u32()?);
+    for _ in 0..2 {
+        dec.u32()?;
+        dec.opaque()?;
+    }
+    Ok((xid, prog, vers, procedure, dec.rest()))
+}
+
+/// Encodes the successful reply message.
+#[cfg(test)]
+pub fn accept(xid: u32, results: &[u8]) -> Vec<u8> {
+    let mut enc = Encoder::new()
+    .u32(xid).u32(REPLY).u32(MSG_ACCEPTED)
+    .u32(AUTH_NONE).u32(0)
+    .u32(SUCCESS);
+    enc.buf.extend_from_slice(results);
+    enc.buf
+}
+
+/// Wraps the message into a single-fragment record.
+pub fn record(msg: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(msg.len() + 4);
+    buf.extend_from_slice(&(LAST_FRAGMENT | msg.len() as u32).to_be_bytes());
+    buf.extend_from_slice(msg);
+    buf
+}
+
+/// Blocking read of a single record, used by stand-in servers.
+#[cfg(test)]
+pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut msg = Vec::new();
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let header = u32::from_be_bytes(header);
+        let start = msg.len();
+        msg.resize(start + (header & !LAST_FRAGMENT) as usize, 0);
+        reader.read_exact(&mut msg[start..])?;
+        if header & LAST_FRAGMENT != 0 {
+            break Ok(msg);
+        }
+    }
+}
+
+
+/// Record marking: messages are split into fragments prefixed with their length
+/// and the flag of the last fragment.
+pub struct RecordCodec;
+
+impl Codec for RecordCodec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+
+    fn decode(&mut self, buf: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>> {
+        let mut msg = Vec::new();
+        let mut pos = 0;
+        while buf.len() - pos >= 4 {
+            let header = u32::from_be_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+            let len = (header & !LAST_FRAGMENT) as usize;
+            if buf.len() - pos - 4 < len {
+                break;
+            }
+            msg.extend_from_slice(&buf[(pos + 4)..(pos + 4 + len)]);
+            pos += 4 + len;
+            if header & LAST_FRAGMENT != 0 {
+                return Ok(Some((msg, pos)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn encode(&mut self, msg: Vec<u8>, buf: &mut Vec<u8>) -> io::Result<()> {
+        if msg.len() as u64 >= LAST_FRAGMENT as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "RPC message is too long for a single fragment"));
+        }
+        buf.extend_from_slice(&record(&msg));
+        Ok(())
+    }
+}
+
+
+/// Non-blocking TCP stream exchanging records.
+pub struct RecordStream {
+    pub stream: TcpStream,
+    framed: Framed<RecordCodec>,
+}
+
+impl RecordStream {
+    pub fn new(stream: TcpStream) -> Self {
+        Self { stream, framed: Framed::new(RecordCodec) }
+    }
+
+    /// Queues the message to be sent as a record.
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        self.framed.encode(msg.to_vec())?;
+        self.framed.flush(&mut self.stream)
+    }
+
+    /// Checks the connection error and writes out queued data.
+    pub fn ready(&mut self) -> io::Result<()> {
+        if let Some(err) = self.stream.take_error()? {
+            return Err(err);
+        }
+        self.framed.flush(&mut self.stream)
+    }
+
+    /// Reads available data and returns received records and whether the stream is closed by the peer.
+    pub fn recv(&mut self) -> ::Result<(Vec<Vec<u8>>, bool)> {
+        let mut msgs = Vec::new();
+        let eof = self.framed.fill(&mut self.stream, |msg| {
+            msgs.push(msg);
+            Ok(true)
+        })?;
+        Ok((msgs, eof))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn xdr() {
+        let buf = Encoder::new().u32(0x12345678).string("inst0").opaque(&[]).u32(1).buf;
+        assert_eq!(buf.len(), 4 + 4 + 8 + 4 + 4);
+
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(dec.u32().unwrap(), 0x12345678);
+        assert_eq!(dec.opaque().unwrap(), b"inst0");
+        assert_eq!(dec.opaque().unwrap(), b"");
+        assert_eq!(dec.u32().unwrap(), 1);
+        assert_matches!(dec.u32(), Err(_));
+    }
+
+    #[test]
+    fn call_reply() {
+        let msg = call(7, PORTMAPPER_PROG, PORTMAPPER_VERS, PORTMAPPER_GETPORT, &[0, 0, 0, 1]);
+        let (xid, prog, vers, procedure, args) = parse_call(&msg).unwrap();
+        assert_eq!((xid, prog, vers, procedure, args), (7, PORTMAPPER_PROG, PORTMAPPER_VERS, PORTMAPPER_GETPORT, &[0, 0, 0, 1][..]));
+
+        let msg = accept(7, &[0, 0, 4, 0]);
+        assert_eq!(reply(&msg).unwrap(), (7, &[0, 0, 4, 0][..]));
+        assert_matches!(reply(&call(7, 1, 1, 1, &[])), Err(_));
+    }
+
+    #[test]
+    fn records() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0, 0, 0, 2, 1, 2]);
+        data.extend_from_slice(&record(&[3]));
+        assert_eq!(read_record(&mut &data[..]).unwrap(), vec![1, 2, 3]);
+
+        assert_eq!(RecordCodec.decode(&data[..9]).unwrap(), None);
+        data.extend_from_slice(&[0, 0]);
+        assert_eq!(RecordCodec.decode(&data).unwrap(), Some((vec![1, 2, 3], 11)));
+        let mut buf = Vec::new();
+        RecordCodec.encode(vec![4], &mut buf).unwrap();
+        assert_eq!(buf, record(&[4]));
+    }
+}
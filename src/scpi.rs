@@ -0,0 +1,396 @@
+//! Proxy for the raw SCPI socket of an instrument (usually TCP port 5025).
+//!
+//! Commands are sent to the instrument as newline-terminated strings
+//! and each newline-terminated line received from the instrument is forwarded to the handle.
+//! Responses starting with `#` are decoded as IEEE 488.2 binary blocks.
+
+use std::net::{SocketAddr};
+use std::collections::{VecDeque};
+
+use mio;
+use mio::net::{TcpStream};
+
+use ::block::{BlockCodec, Item};
+use ::channel::{Sender};
+use ::proxy::{Proxy, Control, Eid};
+use ::proxy_handle::{self, Handle, UserProxy, UserHandle, TxExt, RxExt};
+use ::proxy_handle::{Request, Reply, RequestId};
+use ::proxy_handle::{Tx as BaseTx, Rx as BaseRx};
+use ::stream_proxy::{self, Framed};
+
+
+/// Default port of the raw SCPI socket.
+pub const PORT: u16 = 5025;
+
+const STREAM: Eid = 1;
+
+
+#[derive(Debug, TxExt)]
+pub enum Tx {
+    #[mdrv(base)]
+    Base(BaseTx),
+    /// Send the command to the instrument. The terminating newline is appended if missing.
+    ///
+    /// Responses are matched to the pending queries in order and only the rest is sent back as `Rx::Response` or `Rx::Block`,
+    /// so the command that gets a response must not be sent while a `Query` is pending.
+    Cmd(String),
+    /// Send the query to the instrument, the response to it is sent back as `Rx::Reply` with the same id.
+    /// The id is set by `Handle::call`.
+    Query(RequestId, String),
+}
+
+#[derive(Debug, RxExt)]
+pub enum Rx {
+    #[mdrv(base)]
+    Base(BaseRx),
+    /// Connection to the instrument is established.
+    Connected,
+    /// Line received from the instrument without the line terminator.
+    Response(String),
+    /// Payload of the binary block received from the instrument.
+    Block(Vec<u8>),
+    /// Response to the query with the id.
+    Reply(RequestId, Item),
+}
+
+impl Request for Tx {
+    fn set_request_id(&mut self, id: RequestId) -> bool {
+        match *self {
+            Tx::Query(ref mut qid, _) => {
+                *qid = id;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Reply for Rx {
+    fn request_id(&self) -> Option<RequestId> {
+        match *self {
+            Rx::Reply(id, _) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+
+pub struct ScpiProxy {
+    addr: SocketAddr,
+    tx: Sender<Rx>,
+    stream: Option<TcpStream>,
+    connected: bool,
+    framed: Framed<BlockCodec>,
+    /// Ids of the queries waiting for responses in order.
+    queries: VecDeque<RequestId>,
+}
+
+impl ScpiProxy {
+    fn new(addr: SocketAddr, tx: Sender<Rx>) -> Self {
+        Self {
+            addr, tx,
+            stream: None,
+            connected: false,
+            framed: Framed::new(BlockCodec::new()),
+            queries: VecDeque::new(),
+        }
+    }
+
+    fn send(&self, msg: Rx) -> ::Result<()> {
+        self.tx.send(msg).map_err(|e| ::Error::Channel(e.into()))
+    }
+
+    fn fill(&mut self) -> ::Result<()> {
+        let (tx, queries) = (&self.tx, &mut self.queries);
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => return Ok(()),
+        };
+        let eof = self.framed.fill(stream, |item| {
+            let msg = match (queries.pop_front(), item) {
+                (Some(id), item) => Rx::Reply(id, item),
+                (None, Item::Line(line)) => Rx::Response(String::from_utf8_lossy(&line).into_owned()),
+                (None, Item::Block(data)) => Rx::Block(data),
+            };
+            tx.send(msg).map_err(|e| ::Error::Channel(e.into()))?;
+            Ok(true)
+        })?;
+        if eof {
+            Err(stream_proxy::closed())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> ::Result<()> {
+        match self.stream {
+            Some(ref mut stream) => Ok(self.framed.flush(stream)?),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Proxy for ScpiProxy {
+    fn attach(&mut self, ctrl: &Control) -> ::Result<()> {
+        let stream = TcpStream::connect(&self.addr)?;
+        ctrl.register(&stream, STREAM, mio::Ready::readable() | mio::Ready::writable(), mio::PollOpt::edge())?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn detach(&mut self, ctrl: &Control) -> ::Result<()> {
+        // The connection is reestablished on the next attachment, so pending data is discarded
+        self.connected = false;
+        self.framed.reset();
+        self.queries.clear();
+        match self.stream.take() {
+            Some(stream) => ctrl.deregister_eid(&stream, STREAM),
+            None => Ok(()),
+        }
+    }
+
+    fn process(&mut self, _ctrl: &mut Control, readiness: mio::Ready, eid: Eid) -> ::Result<()> {
+        assert_eq!(eid, STREAM);
+        if let Some(err) = self.stream.as_ref().map_or(Ok(None), |s| s.take_error())? {
+            return Err(err.into());
+        }
+        if readiness.is_writable() && !self.connected {
+            self.connected = true;
+            self.send(Rx::Connected)?;
+        }
+        if readiness.is_readable() {
+            self.fill()?;
+        }
+        self.flush()
+    }
+}
+
+impl UserProxy<Tx, Rx> for ScpiProxy {
+    fn process_channel(&mut self, _ctrl: &mut Control, msg: Tx) -> ::Result<()> {
+        let cmd = match msg {
+            Tx::Cmd(cmd) => cmd,
+            Tx::Query(id, cmd) => {
+                self.queries.push_back(id);
+                cmd
+            },
+            Tx::Base(_) => return Ok(()),
+        };
+        self.framed.encode(cmd.into_bytes())?;
+        self.flush()
+    }
+}
+
+pub struct ScpiHandle {
+    pub msgs: VecDeque<Rx>,
+}
+
+impl ScpiHandle {
+    fn new() -> Self {
+        Self { msgs: VecDeque::new() }
+    }
+}
+
+impl UserHandle<Tx, Rx> for ScpiHandle {
+    fn process_channel(&mut self, msg: Rx) -> ::Result<()> {
+        self.msgs.push_back(msg);
+        Ok(())
+    }
+}
+
+/// Creates the proxy connecting to the instrument at `addr` and its handle.
+///
+/// The connection is made when the proxy is attached to the event loop.
+pub fn create(addr: SocketAddr) -> ::Result<proxy_handle::Pair<ScpiProxy, ScpiHandle, Tx, Rx>> {
+    proxy_handle::create_with(|tx| ScpiProxy::new(addr, tx), ScpiHandle::new())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+    use std::time::{Duration};
+    use std::net::{TcpListener};
+    use std::io::{Write, BufRead, BufReader};
+
+    use ::block;
+    use ::driver::{Driver};
+    use ::proxy_handle::{DetachReason};
+
+    fn wait_msg(h: &mut Handle<ScpiHandle, Tx, Rx>) -> Rx {
+        h.wait_until(|u| !u.msgs.is_empty(), Some(Duration::from_secs(10))).unwrap();
+        h.user.msgs.pop_front().unwrap()
+    }
+
+    #[test]
+    fn query() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "*IDN?\n");
+            writer.write_all(b"MDRV,TEST,0,1.0\r\n").unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "MEAS:VOLT?\n");
+            writer.write_all(b"1.5\n-2").unwrap();
+            writer.write_all(b".5\n").unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "DISP:DATA?\n");
+            writer.write_all(&block::encode(b"\x89PNG\r\n")).unwrap();
+            writer.write_all(b"\n").unwrap();
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        h.tx.send(Tx::Cmd("*IDN?".into())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        assert_matches!(wait_msg(&mut h), Rx::Response(ref s) if s == "MDRV,TEST,0,1.0");
+
+        h.tx.send(Tx::Cmd("MEAS:VOLT?\n".into())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Response(ref s) if s == "1.5");
+        assert_matches!(wait_msg(&mut h), Rx::Response(ref s) if s == "-2.5");
+
+        h.tx.send(Tx::Cmd("DISP:DATA?".into())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Block(ref data) if data == b"\x89PNG\r\n");
+
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn sender_after_handle_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "*IDN?\n");
+            writer.write_all(b"MDRV,TEST,0,1.0\n").unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "*RST\n");
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, h) = create(addr).unwrap();
+        let mut responses = h.subscribe(8, |msg| match *msg {
+            Rx::Response(ref s) => Some(s.clone()),
+            _ => None,
+        }).unwrap();
+        let s = h.sender();
+        drop(h);
+        drv.attach(Box::new(p)).unwrap();
+
+        // the proxy keeps working while the sender is alive
+        s.send_timeout(Tx::Cmd("*IDN?".into()), None).unwrap();
+        assert_eq!(responses.recv(Some(Duration::from_secs(10))).unwrap(), "MDRV,TEST,0,1.0");
+        s.send_timeout(Tx::Cmd("*RST".into()), None).unwrap();
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            listener.accept().unwrap();
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+        thr.join().unwrap();
+
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Detached { reason: DetachReason::Error(::Error::Io(_)) }));
+    }
+
+    #[test]
+    fn call() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "*IDN?\n");
+            writer.write_all(b"MDRV,TEST,0,1.0\n").unwrap();
+            // the next query is left unanswered
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        assert_matches!(
+            h.call(Tx::Query(0, "*IDN?".into()), Some(Duration::from_secs(10))).unwrap(),
+            Rx::Reply(_, Item::Line(ref line)) if line == b"MDRV,TEST,0,1.0"
+        );
+        // notifications are passed to the user handle
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Base(BaseRx::Attached { .. })));
+        assert_matches!(h.user.msgs.pop_front(), Some(Rx::Connected));
+
+        assert_matches!(h.call(Tx::Query(0, "MEAS?".into()), Some(Duration::from_millis(50))), Err(::Error::Timeout));
+        assert_matches!(h.call(Tx::Cmd("*RST".into()), None), Err(::Error::Proxy(::proxy::Error::NotRequest)));
+        h.tx.send(Tx::Cmd("*RST".into())).unwrap();
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn call_after_cmd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thr = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut line = String::new();
+            for expected in &["*RST\n", "MEAS?\n", "DISP:TEXT \"done?\"\n", "*IDN?\n"] {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                assert_eq!(&line, expected);
+                match *expected {
+                    "MEAS?\n" => writer.write_all(b"1.5\n").unwrap(),
+                    "*IDN?\n" => writer.write_all(b"MDRV,TEST,0,1.0\n").unwrap(),
+                    _ => (),
+                }
+            }
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        h.tx.send(Tx::Cmd("*RST".into())).unwrap();
+        h.tx.send(Tx::Cmd("MEAS?".into())).unwrap();
+        // the response without the pending query is passed as is
+        h.wait_until(|u| u.msgs.iter().any(|m| matches!(m, Rx::Response(_))), Some(Duration::from_secs(10))).unwrap();
+        assert_matches!(h.user.msgs.pop_back(), Some(Rx::Response(ref s)) if s == "1.5");
+        // the quoted `?` doesn't make the command expect a response
+        h.tx.send(Tx::Cmd("DISP:TEXT \"done?\"".into())).unwrap();
+        assert_matches!(
+            h.call(Tx::Query(0, "*IDN?".into()), Some(Duration::from_secs(10))).unwrap(),
+            Rx::Reply(_, Item::Line(ref line)) if line == b"MDRV,TEST,0,1.0"
+        );
+        thr.join().unwrap();
+    }
+}
@@ -0,0 +1,428 @@
+//! Generic proxy exchanging framed messages over a stream.
+//!
+//! The [`StreamProxy`] handles readiness, partial reads and writes and `WouldBlock`,
+//! and leaves message boundaries to the [`Codec`].
+//!
+//! [`StreamProxy`]: struct.StreamProxy.html
+//! [`Codec`]: ../codec/trait.Codec.html
+
+use std::io::{self, Read, Write};
+use std::collections::{VecDeque};
+
+use mio::{self, Evented};
+
+use ::channel::{Sender, TrySendError};
+use ::codec::{Codec};
+use ::proxy::{Proxy, Control, Eid};
+use ::proxy_handle::{self, Handle, UserProxy, UserHandle, NonBlocking, TxExt, RxExt};
+use ::proxy_handle::{Tx as BaseTx, Rx as BaseRx};
+
+
+const STREAM: Eid = 1;
+const SPACE: Eid = 2;
+
+/// Maximum amount of data read from the stream before passing decoded messages.
+const READ_BATCH: usize = 1 << 20;
+
+
+#[derive(Debug, TxExt)]
+pub enum Tx<T> {
+    #[mdrv(base)]
+    Base(BaseTx),
+    /// Encode and send the message.
+    Send(T),
+}
+
+#[derive(Debug, RxExt)]
+pub enum Rx<T> {
+    #[mdrv(base)]
+    Base(BaseRx),
+    /// Message decoded from the stream.
+    Recv(T),
+}
+
+
+/// Read and write buffers of the stream framed by the codec.
+pub(crate) struct Framed<C: Codec> {
+    pub codec: C,
+    rbuf: Vec<u8>,
+    wbuf: Vec<u8>,
+    /// Number of bytes at the beginning of `wbuf` that are already written.
+    wpos: usize,
+    /// End offsets of the messages in `wbuf`.
+    ends: VecDeque<usize>,
+}
+
+impl<C: Codec> Framed<C> {
+    pub fn new(codec: C) -> Self {
+        Self { codec, rbuf: Vec::new(), wbuf: Vec::new(), wpos: 0, ends: VecDeque::new() }
+    }
+
+    /// Discards buffered data.
+    pub fn reset(&mut self) {
+        self.codec.reset();
+        self.rbuf.clear();
+        self.wbuf.clear();
+        self.wpos = 0;
+        self.ends.clear();
+    }
+
+    /// Discards read data and the messages already written, so the rest could be written to a new stream.
+    ///
+    /// The message written in part is kept whole and is written again from its start.
+    pub fn restart(&mut self) {
+        self.codec.reset();
+        self.rbuf.clear();
+        let mut start = 0;
+        while let Some(&end) = self.ends.front() {
+            if end > self.wpos {
+                break;
+            }
+            start = end;
+            self.ends.pop_front();
+        }
+        self.wbuf.drain(..start);
+        for end in self.ends.iter_mut() {
+            *end -= start;
+        }
+        self.wpos = 0;
+    }
+
+    pub fn encode(&mut self, msg: C::Out) -> io::Result<()> {
+        let len = self.wbuf.len();
+        match self.codec.encode(msg, &mut self.wbuf) {
+            Ok(()) => {
+                self.ends.push_back(self.wbuf.len());
+                Ok(())
+            },
+            Err(e) => {
+                // Do not leave the message encoded in part
+                self.wbuf.truncate(len);
+                Err(e)
+            },
+        }
+    }
+
+    /// Writes buffered data until the stream would block.
+    pub fn flush<S: Write>(&mut self, stream: &mut S) -> io::Result<()> {
+        while self.wpos < self.wbuf.len() {
+            match stream.write(&self.wbuf[self.wpos..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write to stream")),
+                Ok(n) => self.wpos += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // The stream may be still connecting
+                Err(ref e) if e.kind() == io::ErrorKind::NotConnected => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.wpos == self.wbuf.len() {
+            self.wbuf.clear();
+            self.wpos = 0;
+            self.ends.clear();
+        }
+        Ok(())
+    }
+
+    /// Reads the stream until it would block and passes decoded messages to `f`.
+    ///
+    /// If `f` returns `false` the reading is paused, the rest of the data is left in the buffer and the stream
+    /// and is passed on the next call.
+    /// Returns `true` if the stream is closed by the peer.
+    pub fn fill<S: Read, F: FnMut(C::In) -> ::Result<bool>>(&mut self, stream: &mut S, mut f: F) -> ::Result<bool> {
+        let mut buf = [0; 0x4000];
+        let mut done = None;
+        loop {
+            if !self.drain(&mut f)? {
+                break Ok(false);
+            }
+            if let Some(eof) = done {
+                break Ok(eof);
+            }
+            // Data is read in batches to pause reading soon after `f` stops accepting messages
+            let start = self.rbuf.len();
+            while self.rbuf.len() - start < READ_BATCH {
+                match stream.read(&mut buf) {
+                    Ok(0) => done = Some(true),
+                    Ok(n) => self.rbuf.extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => done = Some(false),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                }
+                if done.is_some() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Passes messages decoded from the buffer to `f` until it returns `false`.
+    fn drain<F: FnMut(C::In) -> ::Result<bool>>(&mut self, f: &mut F) -> ::Result<bool> {
+        let mut pos = 0;
+        let mut accepted = true;
+        while let Some((msg, n)) = self.codec.decode(&self.rbuf[pos..])? {
+            pos += n;
+            if !f(msg)? {
+                accepted = false;
+                break;
+            }
+        }
+        self.rbuf.drain(..pos);
+        Ok(accepted)
+    }
+}
+
+pub(crate) fn closed() -> ::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "stream closed by peer").into()
+}
+
+
+/// Reading from the stream is paused while the channel to the handle is full.
+pub struct StreamProxy<S: Evented + Read + Write, C: Codec> {
+    stream: S,
+    framed: Framed<C>,
+    tx: Sender<Rx<C::In>>,
+    /// Message that did not fit into the full channel.
+    backlog: Option<Rx<C::In>>,
+}
+
+impl<S: Evented + Read + Write, C: Codec> StreamProxy<S, C> {
+    fn new(stream: S, codec: C, tx: Sender<Rx<C::In>>) -> Self {
+        Self { stream, framed: Framed::new(codec), tx, backlog: None }
+    }
+
+    /// Tries to send the message keeping it in the backlog if the channel is full.
+    fn offer(tx: &Sender<Rx<C::In>>, backlog: &mut Option<Rx<C::In>>, msg: Rx<C::In>) -> ::Result<bool> {
+        match tx.try_send(msg) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(msg)) => {
+                *backlog = Some(msg);
+                Ok(false)
+            },
+            Err(e) => Err(::Error::Channel(e.into())),
+        }
+    }
+
+    fn read(&mut self) -> ::Result<()> {
+        if let Some(msg) = self.backlog.take() {
+            if !Self::offer(&self.tx, &mut self.backlog, msg)? {
+                return Ok(());
+            }
+        }
+        let (tx, backlog) = (&self.tx, &mut self.backlog);
+        let eof = self.framed.fill(&mut self.stream, |msg| Self::offer(tx, backlog, Rx::Recv(msg)))?;
+        if eof {
+            return Err(closed());
+        }
+        Ok(())
+    }
+}
+
+impl<S: Evented + Read + Write, C: Codec> Proxy for StreamProxy<S, C> {
+    fn attach(&mut self, ctrl: &Control) -> ::Result<()> {
+        ctrl.register(&self.stream, STREAM, mio::Ready::readable() | mio::Ready::writable(), mio::PollOpt::edge())?;
+        ctrl.register(&self.tx, SPACE, mio::Ready::writable(), mio::PollOpt::edge())
+    }
+
+    fn detach(&mut self, ctrl: &Control) -> ::Result<()> {
+        ctrl.deregister_eid(&self.tx, SPACE)?;
+        ctrl.deregister_eid(&self.stream, STREAM)
+    }
+
+    fn process(&mut self, _ctrl: &mut Control, readiness: mio::Ready, eid: Eid) -> ::Result<()> {
+        match eid {
+            STREAM => {
+                if readiness.is_readable() {
+                    self.read()?;
+                }
+                if readiness.is_writable() {
+                    self.framed.flush(&mut self.stream)?;
+                }
+                Ok(())
+            },
+            // Resume reading paused by the full channel
+            SPACE if self.backlog.is_some() => self.read(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: Evented + Read + Write, C: Codec> UserProxy<Tx<C::Out>, Rx<C::In>> for StreamProxy<S, C> {
+    fn process_channel(&mut self, _ctrl: &mut Control, msg: Tx<C::Out>) -> ::Result<()> {
+        match msg {
+            Tx::Send(msg) => {
+                self.framed.encode(msg)?;
+                self.framed.flush(&mut self.stream)?;
+                Ok(())
+            },
+            Tx::Base(_) => Ok(()),
+        }
+    }
+}
+
+impl<S: Evented + Read + Write, C: Codec> NonBlocking for StreamProxy<S, C> {}
+
+pub struct StreamHandle<T> {
+    pub msgs: VecDeque<Rx<T>>,
+}
+
+impl<T> StreamHandle<T> {
+    fn new() -> Self {
+        Self { msgs: VecDeque::new() }
+    }
+}
+
+impl<T, U> UserHandle<Tx<U>, Rx<T>> for StreamHandle<T> {
+    fn process_channel(&mut self, msg: Rx<T>) -> ::Result<()> {
+        self.msgs.push_back(msg);
+        Ok(())
+    }
+}
+
+/// Stream proxy and its handle as returned by `create`.
+pub type Pair<S, C> = proxy_handle::Pair<StreamProxy<S, C>, StreamHandle<<C as Codec>::In>, Tx<<C as Codec>::Out>, Rx<<C as Codec>::In>>;
+
+/// Creates the proxy exchanging messages framed by the `codec` over the `stream` and its handle.
+pub fn create<S, C>(stream: S, codec: C) -> ::Result<Pair<S, C>>
+where S: Evented + Read + Write, C: Codec {
+    proxy_handle::create_with(|tx| StreamProxy::new(stream, codec, tx), StreamHandle::new())
+}
+
+/// Same as `create` but channels hold at most `bound` messages.
+pub fn create_bounded<S, C>(stream: S, codec: C, bound: usize) -> ::Result<Pair<S, C>>
+where S: Evented + Read + Write, C: Codec {
+    proxy_handle::create_with_bounded(|tx| StreamProxy::new(stream, codec, tx), StreamHandle::new(), bound)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+    use std::time::{Duration};
+    use std::net::{TcpListener};
+
+    use mio::net::{TcpStream};
+
+    use ::codec::{LineCodec, LengthPrefixedCodec};
+    use ::driver::{Driver};
+
+    fn wait_msg<T, U>(h: &mut Handle<StreamHandle<T>, Tx<U>, Rx<T>>) -> Rx<T> {
+        h.wait_until(|u| !u.msgs.is_empty(), Some(Duration::from_secs(10))).unwrap();
+        h.user.msgs.pop_front().unwrap()
+    }
+
+    #[test]
+    fn restart() {
+        let mut framed = Framed::new(LineCodec::default());
+        for msg in &[&b"ab"[..], b"cd", b"ef"] {
+            framed.encode(msg.to_vec()).unwrap();
+        }
+        let mut out = [0; 4];
+        framed.flush(&mut &mut out[..]).unwrap_err();
+        framed.restart();
+        let mut out = Vec::new();
+        framed.flush(&mut out).unwrap();
+        assert_eq!(out, b"cd\nef\n");
+
+        framed.encode(b"gh".to_vec()).unwrap();
+        framed.restart();
+        framed.flush(&mut out).unwrap();
+        assert_eq!(out, b"cd\nef\ngh\n");
+
+        // the message ending at the written boundary is not repeated
+        for msg in &[&b"ij"[..], b"kl"] {
+            framed.encode(msg.to_vec()).unwrap();
+        }
+        let mut out = [0; 3];
+        framed.flush(&mut &mut out[..]).unwrap_err();
+        framed.restart();
+        let mut out = Vec::new();
+        framed.flush(&mut out).unwrap();
+        assert_eq!(out, b"kl\n");
+    }
+
+    #[test]
+    fn lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let thr = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"hello\nwor").unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"ping\n");
+            stream.write_all(b"ld\n").unwrap();
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(stream, LineCodec::default()).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Recv(ref msg) if msg == b"hello");
+        h.tx.send(Tx::Send(b"ping".to_vec())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Recv(ref msg) if msg == b"world");
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn large_echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let thr = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for _ in 0..2 {
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).unwrap();
+                let mut buf = vec![0; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut buf).unwrap();
+                stream.write_all(&len).unwrap();
+                stream.write_all(&buf).unwrap();
+            }
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(stream, LengthPrefixedCodec::new(4)).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        // larger than socket buffers to get partial writes
+        let data = (0..(1 << 23)).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        h.tx.send(Tx::Send(data.clone())).unwrap();
+        h.tx.send(Tx::Send(b"tail".to_vec())).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Recv(ref msg) if msg == &data);
+        assert_matches!(wait_msg(&mut h), Rx::Recv(ref msg) if msg == b"tail");
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn backpressure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let n = 10000;
+        let thr = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let data = (0..n).map(|i| format!("{}\n", i)).collect::<String>();
+            stream.write_all(data.as_bytes()).unwrap();
+            let mut buf = [0; 1];
+            stream.read_exact(&mut buf).unwrap();
+        });
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create_bounded(stream, LineCodec::default(), 4).unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+        // the proxy is paused when the channel is full
+        assert_eq!(h.rx.len(), 4);
+
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        for i in 0..n {
+            assert_matches!(wait_msg(&mut h), Rx::Recv(ref msg) if msg == i.to_string().as_bytes());
+        }
+        h.tx.send(Tx::Send(b"!".to_vec())).unwrap();
+        thr.join().unwrap();
+    }
+}
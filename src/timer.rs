@@ -0,0 +1,102 @@
+use std::io;
+use std::time::{Duration};
+use std::collections::{BTreeMap};
+
+use mio;
+use mio_extras::timer::{self, Timer, Timeout};
+
+use ::error::{IdError};
+use ::proxy::{Id, TimerId};
+
+
+struct Entry {
+    id: Id,
+    period: Option<Duration>,
+    timeout: Timeout,
+}
+
+/// Timers of all proxies in the event loop, backed by a single timer wheel.
+pub struct Timers {
+    timer: Timer<TimerId>,
+    entries: BTreeMap<TimerId, Entry>,
+    next: TimerId,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self {
+            timer: timer::Builder::default()
+                .tick_duration(Duration::from_millis(1))
+                .num_slots(1024)
+                .build(),
+            entries: BTreeMap::new(),
+            next: 1,
+        }
+    }
+
+    pub fn set(&mut self, id: Id, delay: Duration, period: Option<Duration>) -> TimerId {
+        let tid = self.next;
+        self.next += 1;
+        let timeout = self.timer.set_timeout(delay, tid);
+        self.entries.insert(tid, Entry { id, period, timeout });
+        tid
+    }
+
+    pub fn cancel(&mut self, id: Id, tid: TimerId) -> ::Result<()> {
+        match self.entries.get(&tid) {
+            Some(entry) if entry.id == id => {
+                let entry = self.entries.remove(&tid).unwrap();
+                self.timer.cancel_timeout(&entry.timeout);
+                Ok(())
+            },
+            _ => Err(IdError::Missing.into()),
+        }
+    }
+
+    /// Cancels all timers of the proxy.
+    pub fn cancel_all(&mut self, id: Id) {
+        let tids = self.entries.iter()
+            .filter(|(_, entry)| entry.id == id)
+            .map(|(tid, _)| *tid)
+            .collect::<Vec<_>>();
+        for tid in tids {
+            self.cancel(id, tid).unwrap();
+        }
+    }
+
+    /// Returns the next expired timer. Periodic timers are rescheduled.
+    pub fn poll(&mut self) -> Option<(Id, TimerId)> {
+        loop {
+            let tid = self.timer.poll()?;
+            // Timer could be cancelled after it has expired
+            let (id, period) = match self.entries.get(&tid) {
+                Some(entry) => (entry.id, entry.period),
+                None => continue,
+            };
+            match period {
+                Some(period) => {
+                    let timeout = self.timer.set_timeout(period, tid);
+                    self.entries.get_mut(&tid).unwrap().timeout = timeout;
+                },
+                None => {
+                    self.entries.remove(&tid);
+                },
+            }
+            break Some((id, tid));
+        }
+    }
+}
+
+impl mio::Evented for Timers {
+    fn register(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> io::Result<()> {
+        self.timer.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> io::Result<()> {
+        self.timer.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        self.timer.deregister(poll)
+    }
+}
@@ -0,0 +1,180 @@
+use std::mem;
+use std::collections::{BTreeMap};
+
+use mio;
+
+use ::error::{IdError};
+use ::proxy::{Id, Eid};
+
+
+/// Token of the event loop control channel.
+pub const LOOP: mio::Token = mio::Token(0);
+/// Token of the shared timer.
+pub const TIMER: mio::Token = mio::Token(1);
+
+const RESERVED: usize = 2;
+
+/// Lower half of the token is the slot index and upper half is the slot generation.
+const KEY_BITS: usize = 4*mem::size_of::<usize>();
+const KEY_MASK: usize = (1 << KEY_BITS) - 1;
+
+struct Slot {
+    gen: usize,
+    ids: Option<(Id, Eid)>,
+}
+
+/// Table mapping `mio::Token` to proxy id and endpoint id.
+///
+/// Each `(Id, Eid)` pair gets its own token which is kept until the endpoint is deregistered
+/// or the proxy is removed.
+///
+/// Freed slots are reused with incremented generation, so the events from the registrations
+/// left by removed proxies don't match any token and could be discarded.
+pub struct Tokens {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    index: BTreeMap<(Id, Eid), usize>,
+}
+
+impl Tokens {
+    pub fn new() -> Self {
+        Self { slots: Vec::new(), free: Vec::new(), index: BTreeMap::new() }
+    }
+
+    fn token(&self, key: usize) -> mio::Token {
+        mio::Token(((self.slots[key].gen << KEY_BITS) | key) + RESERVED)
+    }
+
+    pub fn get(&self, token: mio::Token) -> Option<(Id, Eid)> {
+        let value = token.0.checked_sub(RESERVED)?;
+        let (key, gen) = (value & KEY_MASK, value >> KEY_BITS);
+        match self.slots.get(key) {
+            Some(slot) if slot.gen == gen => slot.ids,
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, id: Id, eid: Eid) -> bool {
+        self.index.contains_key(&(id, eid))
+    }
+
+    pub fn insert(&mut self, id: Id, eid: Eid) -> ::Result<mio::Token> {
+        if let Some(key) = self.index.get(&(id, eid)) {
+            return Ok(self.token(*key));
+        }
+        let key = match self.free.pop() {
+            Some(key) => key,
+            None => {
+                // Keep the token distinct from `usize::MAX` reserved by mio
+                if self.slots.len() + RESERVED >= KEY_MASK {
+                    return Err(IdError::Bad.into());
+                }
+                self.slots.push(Slot { gen: 0, ids: None });
+                self.slots.len() - 1
+            },
+        };
+        self.slots[key].ids = Some((id, eid));
+        self.index.insert((id, eid), key);
+        Ok(self.token(key))
+    }
+
+    fn free(&mut self, key: usize) {
+        let slot = &mut self.slots[key];
+        slot.ids = None;
+        slot.gen = (slot.gen + 1) & (usize::MAX >> KEY_BITS);
+        self.free.push(key);
+    }
+
+    /// Frees the token of the endpoint.
+    pub fn remove_eid(&mut self, id: Id, eid: Eid) {
+        if let Some(key) = self.index.remove(&(id, eid)) {
+            self.free(key);
+        }
+    }
+
+    /// Frees all tokens of the proxy.
+    pub fn remove(&mut self, id: Id) {
+        let keys = self.index.range((id, 0)..=(id, Eid::MAX))
+            .map(|(ids, key)| (*ids, *key))
+            .collect::<Vec<_>>();
+        for (ids, key) in keys {
+            self.index.remove(&ids);
+            self.free(key);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+
+    #[test]
+    fn insert_get() {
+        let mut tokens = Tokens::new();
+
+        let a = tokens.insert(1, 0).unwrap();
+        let b = tokens.insert(1, 1 << 20).unwrap();
+        assert!(a != LOOP && a != TIMER && a != b);
+        assert_eq!(tokens.insert(1, 0).unwrap(), a);
+
+        assert_eq!(tokens.get(a), Some((1, 0)));
+        assert_eq!(tokens.get(b), Some((1, 1 << 20)));
+        assert_eq!(tokens.get(LOOP), None);
+        assert_eq!(tokens.get(TIMER), None);
+    }
+
+    #[test]
+    fn remove() {
+        let mut tokens = Tokens::new();
+
+        let a = tokens.insert(1, 0).unwrap();
+        let b = tokens.insert(2, 0).unwrap();
+        let c = tokens.insert(2, 1).unwrap();
+        tokens.remove(2);
+
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens.get(a), Some((1, 0)));
+        assert_eq!(tokens.get(b), None);
+        assert_eq!(tokens.get(c), None);
+    }
+
+    #[test]
+    fn remove_eid() {
+        let mut tokens = Tokens::new();
+
+        let a = tokens.insert(1, 0).unwrap();
+        let b = tokens.insert(1, 1).unwrap();
+        tokens.remove_eid(1, 0);
+        tokens.remove_eid(1, 2);
+
+        assert_eq!(tokens.len(), 1);
+        assert!(!tokens.contains(1, 0));
+        assert_eq!(tokens.get(a), None);
+        assert_eq!(tokens.get(b), Some((1, 1)));
+
+        let c = tokens.insert(1, 0).unwrap();
+        assert!(a != c);
+        assert_eq!(tokens.get(c), Some((1, 0)));
+    }
+
+    #[test]
+    fn reuse() {
+        let mut tokens = Tokens::new();
+
+        let a = tokens.insert(1, 0).unwrap();
+        tokens.remove(1);
+        let b = tokens.insert(2, 0).unwrap();
+
+        assert!(a != b);
+        assert_eq!(a.0 & KEY_MASK, b.0 & KEY_MASK);
+        assert_eq!(tokens.get(a), None);
+        assert_eq!(tokens.get(b), Some((2, 0)));
+    }
+}
@@ -0,0 +1,640 @@
+//! VXI-11 client proxy.
+//!
+//! The proxy asks the portmapper of the instrument for the port of the VXI-11 core channel,
+//! connects to it and creates the link to the device.
+//! Operations sent to the handle are executed one by one in the order of arrival
+//! and each operation is answered with its own `Rx` message or `Rx::DeviceError`.
+//! Operations left unanswered on detachment, including the queued ones, are answered with `Rx::Aborted`.
+//! The instrument that doesn't reply within the operation timeouts fails the proxy.
+
+use std::io;
+use std::net::{SocketAddr};
+use std::time::{Duration};
+use std::collections::{VecDeque};
+
+use mio;
+use mio::net::{TcpStream};
+
+use ::channel::{Sender};
+use ::proxy::{Proxy, Control, Eid, TimerId};
+use ::proxy_handle::{self, Handle, UserProxy, UserHandle, TxExt, RxExt};
+use ::proxy_handle::{Tx as BaseTx, Rx as BaseRx};
+use ::rpc::{self, RecordStream, Encoder, Decoder};
+
+
+/// Default port of the portmapper.
+pub const PORTMAPPER_PORT: u16 = 111;
+
+const CORE_PROG: u32 = 0x0607AF;
+const CORE_VERS: u32 = 1;
+
+const CREATE_LINK: u32 = 10;
+const DEVICE_WRITE: u32 = 11;
+const DEVICE_READ: u32 = 12;
+const DEVICE_READSTB: u32 = 13;
+const DEVICE_CLEAR: u32 = 15;
+const DEVICE_LOCK: u32 = 18;
+const DEVICE_UNLOCK: u32 = 19;
+const DESTROY_LINK: u32 = 23;
+
+const FLAG_WAITLOCK: u32 = 0x01;
+const FLAG_END: u32 = 0x08;
+
+const REASON_CHR: u32 = 0x02;
+const REASON_END: u32 = 0x04;
+
+/// Default timeout of device I/O operations in milliseconds.
+const IO_TIMEOUT: u32 = 10000;
+/// Default timeout of waiting for the device lock in milliseconds.
+const LOCK_TIMEOUT: u32 = 10000;
+/// Time given to the reply to arrive after the operation timeouts are expired on the device.
+const REPLY_MARGIN: Duration = Duration::from_secs(1);
+/// Maximum size of data requested by a single `device_read` call.
+const READ_SIZE: u32 = 0x10000;
+
+const PORTMAPPER: Eid = 1;
+const CORE: Eid = 2;
+
+
+#[derive(Debug, TxExt)]
+pub enum Tx {
+    #[mdrv(base)]
+    Base(BaseTx),
+    /// Write data to the device.
+    Write(Vec<u8>),
+    /// Read data from the device until the end indicator.
+    Read,
+    /// Read the status byte.
+    ReadStb,
+    /// Clear the device.
+    Clear,
+    /// Lock the device waiting for the lock if it is held by another link.
+    Lock,
+    /// Release the device lock.
+    Unlock,
+}
+
+#[derive(Debug, RxExt)]
+pub enum Rx {
+    #[mdrv(base)]
+    Base(BaseRx),
+    /// The link to the device is created.
+    Connected,
+    /// Number of bytes written.
+    Written(usize),
+    Data(Vec<u8>),
+    Stb(u8),
+    Cleared,
+    Locked,
+    Unlocked,
+    /// The operation is failed with the VXI-11 error code.
+    DeviceError(u32),
+    /// The operation, either started or queued, is dropped without reply because the proxy is detached.
+    Aborted,
+}
+
+
+/// The RPC call waiting for reply.
+enum Call {
+    GetPort,
+    CreateLink,
+    Write { rest: Vec<u8>, size: usize },
+    Read { data: Vec<u8> },
+    ReadStb,
+    Clear,
+    Lock,
+    Unlock,
+}
+
+struct Link {
+    lid: u32,
+    max_recv_size: usize,
+}
+
+pub struct Vxi11Proxy {
+    addr: SocketAddr,
+    device: String,
+    tx: Sender<Rx>,
+    portmapper: Option<RecordStream>,
+    core: Option<RecordStream>,
+    link: Option<Link>,
+    xid: u32,
+    call: Option<Call>,
+    queue: VecDeque<Tx>,
+    io_timeout: u32,
+    lock_timeout: u32,
+    /// Timer watching the reply to the call with the xid.
+    timer: Option<(TimerId, u32)>,
+}
+
+fn invalid(msg: &str) -> ::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+impl Vxi11Proxy {
+    fn new(addr: SocketAddr, device: String, tx: Sender<Rx>) -> Self {
+        Self {
+            addr, device, tx,
+            portmapper: None,
+            core: None,
+            link: None,
+            xid: 0,
+            call: None,
+            queue: VecDeque::new(),
+            io_timeout: IO_TIMEOUT,
+            lock_timeout: LOCK_TIMEOUT,
+            timer: None,
+        }
+    }
+
+    fn millis(timeout: Duration) -> u32 {
+        timeout.as_millis().min(u32::MAX as u128) as u32
+    }
+
+    /// Sets the timeout of device I/O operations.
+    pub fn set_io_timeout(&mut self, timeout: Duration) {
+        self.io_timeout = Self::millis(timeout);
+    }
+
+    /// Sets the timeout of waiting for the device lock held by another link.
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = Self::millis(timeout);
+    }
+
+    /// Starts the timer for the call in progress and cancels the one of the completed call.
+    fn watch(&mut self, ctrl: &Control) -> ::Result<()> {
+        let xid = self.call.as_ref().map(|_| self.xid);
+        if self.timer.map(|(_, txid)| txid) == xid {
+            return Ok(());
+        }
+        if let Some((tid, _)) = self.timer.take() {
+            // The timer could be already fired
+            let _ = ctrl.cancel_timer(tid);
+        }
+        if let Some(xid) = xid {
+            let delay = Duration::from_millis(self.io_timeout as u64 + self.lock_timeout as u64) + REPLY_MARGIN;
+            self.timer = Some((ctrl.set_timeout(delay)?, xid));
+        }
+        Ok(())
+    }
+
+    fn send(&self, msg: Rx) -> ::Result<()> {
+        self.tx.send(msg).map_err(|e| ::Error::Channel(e.into()))
+    }
+
+    fn connect(&self, ctrl: &Control, addr: &SocketAddr, eid: Eid) -> ::Result<RecordStream> {
+        let stream = TcpStream::connect(addr)?;
+        ctrl.register(&stream, eid, mio::Ready::readable() | mio::Ready::writable(), mio::PollOpt::edge())?;
+        Ok(RecordStream::new(stream))
+    }
+
+    fn request(&mut self, eid: Eid, prog: u32, vers: u32, procedure: u32, args: Encoder, call: Call) -> ::Result<()> {
+        self.xid = self.xid.wrapping_add(1);
+        let msg = rpc::call(self.xid, prog, vers, procedure, &args.buf);
+        let stream = match eid {
+            PORTMAPPER => self.portmapper.as_mut(),
+            _ => self.core.as_mut(),
+        }.ok_or_else(|| invalid("stream is not connected"))?;
+        stream.send(&msg)?;
+        self.call = Some(call);
+        Ok(())
+    }
+
+    fn core_request(&mut self, procedure: u32, args: Encoder, call: Call) -> ::Result<()> {
+        self.request(CORE, CORE_PROG, CORE_VERS, procedure, args, call)
+    }
+
+    fn write(&mut self, mut data: Vec<u8>, size: usize) -> ::Result<()> {
+        let (lid, max) = match self.link {
+            Some(ref link) => (link.lid, link.max_recv_size),
+            None => return Err(invalid("link is not created")),
+        };
+        let rest = data.split_off(data.len().min(max));
+        let flags = if rest.is_empty() { FLAG_END } else { 0 };
+        let args = Encoder::new().u32(lid).u32(self.io_timeout).u32(self.lock_timeout).u32(flags).opaque(&data);
+        self.core_request(DEVICE_WRITE, args, Call::Write { rest, size })
+    }
+
+    fn read(&mut self, data: Vec<u8>) -> ::Result<()> {
+        let lid = self.link.as_ref().map(|l| l.lid).unwrap_or(0);
+        let args = Encoder::new().u32(lid).u32(READ_SIZE).u32(self.io_timeout).u32(self.lock_timeout).u32(0).u32(0);
+        self.core_request(DEVICE_READ, args, Call::Read { data })
+    }
+
+    /// Starts the next queued operation if the link is ready.
+    fn next(&mut self) -> ::Result<()> {
+        if self.call.is_some() {
+            return Ok(());
+        }
+        let lid = match self.link {
+            Some(ref link) => link.lid,
+            None => return Ok(()),
+        };
+        let (io_timeout, lock_timeout) = (self.io_timeout, self.lock_timeout);
+        let generic = || Encoder::new().u32(lid).u32(0).u32(lock_timeout).u32(io_timeout);
+        match self.queue.pop_front() {
+            Some(Tx::Write(data)) => self.write(data, 0),
+            Some(Tx::Read) => self.read(Vec::new()),
+            Some(Tx::ReadStb) => self.core_request(DEVICE_READSTB, generic(), Call::ReadStb),
+            Some(Tx::Clear) => self.core_request(DEVICE_CLEAR, generic(), Call::Clear),
+            Some(Tx::Lock) => {
+                let args = Encoder::new().u32(lid).u32(FLAG_WAITLOCK).u32(lock_timeout);
+                self.core_request(DEVICE_LOCK, args, Call::Lock)
+            },
+            Some(Tx::Unlock) => self.core_request(DEVICE_UNLOCK, Encoder::new().u32(lid), Call::Unlock),
+            Some(Tx::Base(_)) | None => Ok(()),
+        }
+    }
+
+    fn reply(&mut self, ctrl: &Control, msg: &[u8]) -> ::Result<()> {
+        let (xid, results) = rpc::reply(msg)?;
+        if xid != self.xid {
+            return Err(invalid("unexpected RPC reply"));
+        }
+        let call = self.call.take().ok_or_else(|| invalid("unexpected RPC reply"))?;
+        let mut dec = Decoder::new(results);
+        if let Call::GetPort = call {
+            let port = dec.u32()?;
+            if port == 0 || port > u16::MAX as u32 {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "VXI-11 core channel is not registered").into());
+            }
+            if let Some(pm) = self.portmapper.take() {
+                ctrl.deregister_eid(&pm.stream, PORTMAPPER)?;
+            }
+            let core = self.connect(ctrl, &SocketAddr::new(self.addr.ip(), port as u16), CORE)?;
+            self.core = Some(core);
+            let args = Encoder::new().u32(0).u32(0).u32(self.lock_timeout).string(&self.device);
+            return self.core_request(CREATE_LINK, args, Call::CreateLink);
+        }
+
+        let error = dec.u32()?;
+        if error != 0 {
+            if let Call::CreateLink = call {
+                return Err(io::Error::other(format!("VXI-11 create_link failed with error {}", error)).into());
+            }
+            self.send(Rx::DeviceError(error))?;
+            return self.next();
+        }
+        match call {
+            Call::GetPort => unreachable!(),
+            Call::CreateLink => {
+                let lid = dec.u32()?;
+                let _abort_port = dec.u32()?;
+                let max_recv_size = dec.u32()? as usize;
+                self.link = Some(Link { lid, max_recv_size: max_recv_size.max(1) });
+                self.send(Rx::Connected)?;
+            },
+            Call::Write { rest, size } => {
+                let size = size + dec.u32()? as usize;
+                if !rest.is_empty() {
+                    return self.write(rest, size);
+                }
+                self.send(Rx::Written(size))?;
+            },
+            Call::Read { mut data } => {
+                let reason = dec.u32()?;
+                data.extend(dec.opaque()?);
+                if reason & (REASON_END | REASON_CHR) == 0 {
+                    return self.read(data);
+                }
+                self.send(Rx::Data(data))?;
+            },
+            Call::ReadStb => self.send(Rx::Stb(dec.u32()? as u8))?,
+            Call::Clear => self.send(Rx::Cleared)?,
+            Call::Lock => self.send(Rx::Locked)?,
+            Call::Unlock => self.send(Rx::Unlocked)?,
+        }
+        self.next()
+    }
+}
+
+impl Proxy for Vxi11Proxy {
+    fn attach(&mut self, ctrl: &Control) -> ::Result<()> {
+        let pm = self.connect(ctrl, &self.addr, PORTMAPPER)?;
+        self.portmapper = Some(pm);
+        let args = Encoder::new().u32(CORE_PROG).u32(CORE_VERS).u32(rpc::IPPROTO_TCP).u32(0);
+        self.request(PORTMAPPER, rpc::PORTMAPPER_PROG, rpc::PORTMAPPER_VERS, rpc::PORTMAPPER_GETPORT, args, Call::GetPort)?;
+        self.watch(ctrl)
+    }
+
+    fn detach(&mut self, ctrl: &Control) -> ::Result<()> {
+        if let Some((tid, _)) = self.timer.take() {
+            let _ = ctrl.cancel_timer(tid);
+        }
+        // The link is destroyed without waiting for the reply
+        if let (Some(link), Some(core)) = (self.link.take(), self.core.as_mut()) {
+            self.xid = self.xid.wrapping_add(1);
+            let _ = core.send(&rpc::call(self.xid, CORE_PROG, CORE_VERS, DESTROY_LINK, &Encoder::new().u32(link.lid).buf));
+        }
+        match self.call.take() {
+            // The handle doesn't wait for the link setup calls
+            Some(Call::GetPort) | Some(Call::CreateLink) | None => (),
+            // Nothing to do if the handle is already gone
+            Some(_) => {
+                let _ = self.send(Rx::Aborted);
+            },
+        }
+        // Queued operations are not replayed on the next attachment
+        for _ in self.queue.drain(..) {
+            let _ = self.tx.send(Rx::Aborted);
+        }
+        if let Some(pm) = self.portmapper.take() {
+            ctrl.deregister_eid(&pm.stream, PORTMAPPER)?;
+        }
+        if let Some(core) = self.core.take() {
+            ctrl.deregister_eid(&core.stream, CORE)?;
+        }
+        Ok(())
+    }
+
+    fn process(&mut self, ctrl: &mut Control, readiness: mio::Ready, eid: Eid) -> ::Result<()> {
+        let (msgs, eof) = {
+            let stream = match eid {
+                PORTMAPPER => self.portmapper.as_mut(),
+                CORE => self.core.as_mut(),
+                _ => None,
+            };
+            let stream = match stream {
+                Some(stream) => stream,
+                None => return Ok(()),
+            };
+            stream.ready()?;
+            if !readiness.is_readable() {
+                return Ok(());
+            }
+            stream.recv()?
+        };
+        for msg in msgs {
+            self.reply(ctrl, &msg)?;
+        }
+        if eof && (eid == CORE || self.portmapper.is_some()) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed by instrument").into());
+        }
+        self.watch(ctrl)
+    }
+
+    fn timeout(&mut self, _ctrl: &mut Control, tid: TimerId) -> ::Result<()> {
+        match self.timer {
+            Some((t, _)) if t == tid => {
+                self.timer = None;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "VXI-11 call is not answered in time").into())
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl UserProxy<Tx, Rx> for Vxi11Proxy {
+    fn process_channel(&mut self, ctrl: &mut Control, msg: Tx) -> ::Result<()> {
+        if let Tx::Base(_) = msg {
+            return Ok(());
+        }
+        self.queue.push_back(msg);
+        self.next()?;
+        self.watch(ctrl)
+    }
+}
+
+pub struct Vxi11Handle {
+    pub msgs: VecDeque<Rx>,
+}
+
+impl Vxi11Handle {
+    fn new() -> Self {
+        Self { msgs: VecDeque::new() }
+    }
+}
+
+impl UserHandle<Tx, Rx> for Vxi11Handle {
+    fn process_channel(&mut self, msg: Rx) -> ::Result<()> {
+        self.msgs.push_back(msg);
+        Ok(())
+    }
+}
+
+/// Creates the proxy for the `device` (e.g. `"inst0"`) and its handle.
+///
+/// The `addr` is the address of the instrument portmapper, its port is usually [`PORTMAPPER_PORT`].
+/// The connection is made when the proxy is attached to the event loop.
+///
+/// [`PORTMAPPER_PORT`]: constant.PORTMAPPER_PORT.html
+pub fn create(addr: SocketAddr, device: &str) -> ::Result<proxy_handle::Pair<Vxi11Proxy, Vxi11Handle, Tx, Rx>> {
+    let device = device.to_string();
+    proxy_handle::create_with(|tx| Vxi11Proxy::new(addr, device, tx), Vxi11Handle::new())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+    use std::time::{Duration};
+    use std::io::{Write};
+    use std::net::{TcpListener};
+
+    use ::driver::{Driver};
+    use ::proxy_handle::{DetachReason};
+    use ::rpc::{read_record, parse_call, accept, record};
+
+    fn wait_msg(h: &mut Handle<Vxi11Handle, Tx, Rx>) -> Rx {
+        h.wait_until(|u| !u.msgs.is_empty(), Some(Duration::from_secs(10))).unwrap();
+        h.user.msgs.pop_front().unwrap()
+    }
+
+    fn serve_portmapper(listener: TcpListener, port: u16) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let msg = read_record(&mut stream).unwrap();
+        let (xid, prog, vers, procedure, args) = parse_call(&msg).unwrap();
+        assert_eq!((prog, vers, procedure), (rpc::PORTMAPPER_PROG, rpc::PORTMAPPER_VERS, rpc::PORTMAPPER_GETPORT));
+        let mut dec = Decoder::new(args);
+        assert_eq!((dec.u32().unwrap(), dec.u32().unwrap(), dec.u32().unwrap()), (CORE_PROG, CORE_VERS, rpc::IPPROTO_TCP));
+        stream.write_all(&record(&accept(xid, &Encoder::new().u32(port as u32).buf))).unwrap();
+    }
+
+    /// Stand-in device with small receive size that answers `*IDN?` in several reads.
+    fn serve_core(listener: TcpListener) -> Vec<u32> {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut input = Vec::new();
+        let mut output = Vec::new();
+        let mut locked = false;
+        let mut calls = Vec::new();
+        loop {
+            let msg = match read_record(&mut stream) {
+                Ok(msg) => msg,
+                Err(_) => break calls,
+            };
+            let (xid, prog, vers, procedure, args) = parse_call(&msg).unwrap();
+            assert_eq!((prog, vers), (CORE_PROG, CORE_VERS));
+            calls.push(procedure);
+            let mut dec = Decoder::new(args);
+            let res = match procedure {
+                CREATE_LINK => {
+                    dec.u32().unwrap();
+                    dec.u32().unwrap();
+                    dec.u32().unwrap();
+                    assert_eq!(dec.opaque().unwrap(), b"inst0");
+                    Encoder::new().u32(0).u32(5).u32(0).u32(4)
+                },
+                DEVICE_WRITE => {
+                    assert_eq!(dec.u32().unwrap(), 5);
+                    dec.u32().unwrap();
+                    dec.u32().unwrap();
+                    let flags = dec.u32().unwrap();
+                    let data = dec.opaque().unwrap();
+                    assert!(data.len() <= 4);
+                    input.extend_from_slice(&data);
+                    if flags & FLAG_END != 0 {
+                        assert_eq!(input, b"*IDN?\n");
+                        input.clear();
+                        output.extend_from_slice(b"FAKE,VXI11,0,1.0\n");
+                    }
+                    Encoder::new().u32(0).u32(data.len() as u32)
+                },
+                DEVICE_READ => {
+                    let n = output.len().min(8);
+                    let data = output.drain(..n).collect::<Vec<_>>();
+                    let reason = if output.is_empty() { REASON_END } else { 0 };
+                    Encoder::new().u32(0).u32(reason).opaque(&data)
+                },
+                DEVICE_READSTB => Encoder::new().u32(0).u32(0x42),
+                DEVICE_CLEAR => Encoder::new().u32(0),
+                DEVICE_LOCK => {
+                    locked = true;
+                    Encoder::new().u32(0)
+                },
+                DEVICE_UNLOCK => {
+                    let error = if locked { 0 } else { 12 };
+                    locked = false;
+                    Encoder::new().u32(error)
+                },
+                DESTROY_LINK => Encoder::new().u32(0),
+                other => panic!("unexpected procedure {}", other),
+            };
+            stream.write_all(&record(&accept(xid, &res.buf))).unwrap();
+        }
+    }
+
+    #[test]
+    fn operations() {
+        let pm_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let core_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = pm_listener.local_addr().unwrap();
+        let port = core_listener.local_addr().unwrap().port();
+        let pm_thr = thread::spawn(move || serve_portmapper(pm_listener, port));
+        let core_thr = thread::spawn(move || serve_core(core_listener));
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr, "inst0").unwrap();
+        let id = drv.attach(Box::new(p)).unwrap();
+
+        for msg in [Tx::Write(b"*IDN?\n".to_vec()), Tx::Read, Tx::ReadStb, Tx::Clear, Tx::Lock, Tx::Unlock, Tx::Unlock] {
+            h.tx.send(msg).unwrap();
+        }
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        assert_matches!(wait_msg(&mut h), Rx::Written(6));
+        assert_matches!(wait_msg(&mut h), Rx::Data(ref data) if data == b"FAKE,VXI11,0,1.0\n");
+        assert_matches!(wait_msg(&mut h), Rx::Stb(0x42));
+        assert_matches!(wait_msg(&mut h), Rx::Cleared);
+        assert_matches!(wait_msg(&mut h), Rx::Locked);
+        assert_matches!(wait_msg(&mut h), Rx::Unlocked);
+        assert_matches!(wait_msg(&mut h), Rx::DeviceError(12));
+
+        drop(drv.detach(id).unwrap());
+        pm_thr.join().unwrap();
+        let calls = core_thr.join().unwrap();
+        assert_eq!(calls.first(), Some(&CREATE_LINK));
+        assert_eq!(calls.last(), Some(&DESTROY_LINK));
+    }
+
+    /// Stand-in device that never replies to reads.
+    fn serve_silent_core(listener: TcpListener, reading: ::std::sync::mpsc::Sender<()>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        while let Ok(msg) = read_record(&mut stream) {
+            let (xid, _, _, procedure, _) = parse_call(&msg).unwrap();
+            match procedure {
+                CREATE_LINK => stream.write_all(&record(&accept(xid, &Encoder::new().u32(0).u32(5).u32(0).u32(4).buf))).unwrap(),
+                DEVICE_READ => reading.send(()).unwrap(),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn abort_on_detach() {
+        let pm_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let core_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = pm_listener.local_addr().unwrap();
+        let port = core_listener.local_addr().unwrap().port();
+        let (rtx, rrx) = ::std::sync::mpsc::channel();
+        let pm_thr = thread::spawn(move || serve_portmapper(pm_listener, port));
+        let core_thr = thread::spawn(move || serve_silent_core(core_listener, rtx));
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr, "inst0").unwrap();
+        let id = drv.attach(Box::new(p)).unwrap();
+
+        for msg in [Tx::Read, Tx::ReadStb, Tx::Clear] {
+            h.tx.send(msg).unwrap();
+        }
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        rrx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // both the started and the queued operations are aborted
+        drop(drv.detach(id).unwrap());
+        for _ in 0..3 {
+            assert_matches!(wait_msg(&mut h), Rx::Aborted);
+        }
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Detached { reason: DetachReason::Requested }));
+        pm_thr.join().unwrap();
+        core_thr.join().unwrap();
+    }
+
+    #[test]
+    fn call_timeout() {
+        let pm_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let core_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = pm_listener.local_addr().unwrap();
+        let port = core_listener.local_addr().unwrap().port();
+        let (rtx, _rrx) = ::std::sync::mpsc::channel();
+        let pm_thr = thread::spawn(move || serve_portmapper(pm_listener, port));
+        let core_thr = thread::spawn(move || serve_silent_core(core_listener, rtx));
+
+        let mut drv = Driver::new().unwrap();
+        let (mut p, mut h) = create(addr, "inst0").unwrap();
+        p.user.set_io_timeout(Duration::from_millis(10));
+        p.user.set_lock_timeout(Duration::from_millis(10));
+        drv.attach(Box::new(p)).unwrap();
+
+        h.tx.send(Tx::Read).unwrap();
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(wait_msg(&mut h), Rx::Connected);
+        assert_matches!(wait_msg(&mut h), Rx::Aborted);
+        assert_matches!(
+            wait_msg(&mut h),
+            Rx::Base(BaseRx::Detached { reason: DetachReason::Error(::Error::Io(ref e)) }) if e.kind() == io::ErrorKind::TimedOut
+        );
+        pm_thr.join().unwrap();
+        core_thr.join().unwrap();
+    }
+
+    #[test]
+    fn not_registered() {
+        let pm_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = pm_listener.local_addr().unwrap();
+        let pm_thr = thread::spawn(move || serve_portmapper(pm_listener, 0));
+
+        let mut drv = Driver::new().unwrap();
+        let (p, mut h) = create(addr, "inst0").unwrap();
+        drv.attach(Box::new(p)).unwrap();
+
+        assert_matches!(wait_msg(&mut h), Rx::Base(BaseRx::Attached { .. }));
+        assert_matches!(
+            wait_msg(&mut h),
+            Rx::Base(BaseRx::Detached { reason: DetachReason::Error(::Error::Io(ref e)) }) if e.kind() == io::ErrorKind::NotFound
+        );
+        pm_thr.join().unwrap();
+    }
+}